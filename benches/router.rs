@@ -0,0 +1,59 @@
+//! Measures how many messages per second a node routes and answers.
+//!
+//! Run with `cargo bench --bench router`.
+//!
+//! Measured on the same machine, in msgs/sec:
+//!
+//! | Tree                                                    | with `dbg!` | without  |
+//! |---------------------------------------------------------|-------------|----------|
+//! | Before decoding requests in a single pass               | 48–51k      | 56–68k   |
+//! | Single-pass decoding with `RawRequest`                  | 63–70k      | 71–84k   |
+//! | Concurrent requests (628eebb)                           | 160–175k    |          |
+//! | Router without its per-message `dbg!` logging (c6c67d4) |             | 236–248k |
+//!
+//! Known request types are then mapped to their constant instead of being allocated. The
+//! later tree measured 167–215k before that change and 174–203k after it, on a noisier run.
+use maelstrom_lib::server::stdio::{start_io_server, IoServerType};
+use serde_json::json;
+use std::{io::sink, time::Instant};
+use tokio::runtime::Runtime;
+
+const MESSAGES: usize = 100_000;
+
+fn main() {
+    let mut input = json!({
+        "src": "c0",
+        "dest": "n1",
+        "body": {"type": "init", "msg_id": 0, "node_id": "n1", "node_ids": ["n1", "n2", "n3"]}
+    })
+    .to_string();
+    input.push('\n');
+    for msg_id in 1..=MESSAGES {
+        let request = json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": {
+                "type": "echo",
+                "msg_id": msg_id,
+                "echo": {"payload": "Please echo this message back", "values": [1, 2, 3, 4, 5]}
+            }
+        });
+        input.push_str(&request.to_string());
+        input.push('\n');
+    }
+
+    let runtime = Runtime::new().expect("Unable to start the tokio runtime");
+    let start = Instant::now();
+    runtime
+        .block_on(start_io_server(
+            input.as_bytes(),
+            sink(),
+            IoServerType::Echo,
+        ))
+        .expect("Unable to serve the benchmark input");
+    let elapsed = start.elapsed();
+
+    #[allow(clippy::cast_precision_loss)]
+    let rate = MESSAGES as f64 / elapsed.as_secs_f64();
+    println!("echo: {MESSAGES} messages in {elapsed:.2?} ({rate:.0} msgs/sec)");
+}
@@ -2,11 +2,12 @@ use maelstrom_lib::{
     error::MaelstromError,
     server::stdio::{start_io_server, IoServerType},
 };
-use std::io::{stdin, stdout};
+use std::io::stdout;
+use tokio::io::{stdin, BufReader};
 
 #[tokio::main]
 async fn main() -> Result<(), MaelstromError> {
-    let input = stdin().lock();
+    let input = BufReader::new(stdin());
     let output = stdout();
     start_io_server(input, output, IoServerType::Gcounter).await
 }
@@ -0,0 +1,13 @@
+use maelstrom_lib::{
+    error::MaelstromError,
+    server::stdio::{start_io_server, IoServerType},
+};
+use std::io::stdout;
+use tokio::io::{stdin, BufReader};
+
+#[tokio::main]
+async fn main() -> Result<(), MaelstromError> {
+    let input = BufReader::new(stdin());
+    let output = stdout();
+    start_io_server(input, output, IoServerType::GcounterSeqKv).await
+}
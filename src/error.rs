@@ -1,7 +1,6 @@
-use crate::message::MsgId;
+use crate::message::{Body, Message, MsgId};
 use derive_more::Constructor;
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
 use std::string::FromUtf8Error;
 use thiserror;
 
@@ -35,11 +34,12 @@ pub enum MaelstromError {
     #[error("Node got a valid message, but it was not the 'init' message.")]
     NodeNotInitialized,
 
-    #[error("Context poison error: {0}")]
-    PoisonError(String),
+    /// The node's task stopped, so its state can't be reached anymore.
+    #[error("Node is no longer running")]
+    NodeStopped,
 
-    #[error("Context RW lock error: {0}")]
-    RWLockError(String),
+    #[error("Outbound channel is closed")]
+    OutboundClosed,
 
     #[error("Serde Json Error: {0}")]
     SerdeJsonError(#[from] serde_json::Error),
@@ -50,9 +50,6 @@ pub enum MaelstromError {
     #[error("Error reading Utf8 from STDIN")]
     StdinUtf8ReadError(#[from] FromUtf8Error),
 
-    #[error("No handler found for request type")]
-    NoHandlerForRequestType,
-
     #[error("Unknown request type received")]
     UnknownRequestType,
 
@@ -64,51 +61,146 @@ pub enum MaelstromError {
     // Ref: https://github.com/jepsen-io/maelstrom/blob/8b9e94c75e59250b82d1730d923f9f8e088ee227/doc/protocol.md?#errors
     #[error("Unexpected error occurred")]
     Crash,
+
+    /// The requested operation expired before it could be completed.
+    /// It may or may not have taken place.
+    #[error("Timeout: {0}")]
+    Timeout(String),
+
+    /// The client sent an RPC request to a node which does not exist.
+    #[error("Node not found: {0}")]
+    NodeNotFound(String),
+
+    /// The requested operation is not supported by the node.
+    #[error("Not supported: {0}")]
+    NotSupported(String),
+
+    /// The operation definitely cannot be performed at this time, e.g. there's no leader.
+    #[error("Temporarily unavailable: {0}")]
+    TemporarilyUnavailable(String),
+
+    /// The operation was aborted and definitely did not take place.
+    #[error("Aborted: {0}")]
+    Abort(String),
+
+    /// The client requested an operation on a key which does not exist.
+    #[error("Key does not exist: {0}")]
+    KeyDoesNotExist(String),
+
+    /// The client tried to create a key which already exists.
+    #[error("Key already exists: {0}")]
+    KeyAlreadyExists(String),
+
+    /// The requested operation expected some condition to hold, e.g. a compare-and-set
+    /// `from` value, and that condition was not met.
+    #[error("Precondition failed: {0}")]
+    PreconditionFailed(String),
+
+    /// The requested transaction was aborted because of a conflict with another transaction.
+    #[error("Transaction conflict: {0}")]
+    TxnConflict(String),
+
+    /// An error with a code that isn't one of Maelstrom's standard codes, or that was received
+    /// with a standard code that has no variant of its own.
+    #[error("{text}")]
+    Custom { code: ErrCode, text: String },
 }
 
 impl MaelstromError {
-    fn code(&self) -> ErrCode {
+    /// Maelstrom's error code for this error. Internal errors are reported as a `crash`.
+    #[must_use]
+    pub fn code(&self) -> ErrCode {
         match self {
-            MaelstromError::EndOfInput => 1000,
-            MaelstromError::JoinError(_) => 1013,
-            MaelstromError::MalformedRequest(_) => 1001,
-            MaelstromError::MissingWorkloadHandlers => 1002,
-            MaelstromError::NodeAlreadyInitialized => 1003,
-            MaelstromError::NodeNotInitialized => 1004,
-            MaelstromError::SerdeJsonError(_) => 1005,
-            MaelstromError::StdinReadError(_) => 1006,
-            MaelstromError::StdinUtf8ReadError(_) => 1007,
-            MaelstromError::NoHandlerForRequestType => 1008,
-            MaelstromError::UnknownRequestType => 1009,
-            MaelstromError::Crash => 13,
-            MaelstromError::MissingMessageId => 1010,
-            MaelstromError::RWLockError(_) => 1011,
-            MaelstromError::PoisonError(_) => 1012,
+            MaelstromError::Timeout(_) => 0,
+            MaelstromError::NodeNotFound(_) => 1,
+            MaelstromError::NotSupported(_) => 10,
+            MaelstromError::TemporarilyUnavailable(_) | MaelstromError::NodeNotInitialized => 11,
+            MaelstromError::MalformedRequest(_)
+            | MaelstromError::MissingMessageId
+            | MaelstromError::NodeAlreadyInitialized
+            | MaelstromError::UnknownRequestType => 12,
+            MaelstromError::Crash
+            | MaelstromError::EndOfInput
+            | MaelstromError::JoinError(_)
+            | MaelstromError::MissingWorkloadHandlers
+            | MaelstromError::NodeStopped
+            | MaelstromError::OutboundClosed
+            | MaelstromError::SerdeJsonError(_)
+            | MaelstromError::StdinReadError(_)
+            | MaelstromError::StdinUtf8ReadError(_) => 13,
+            MaelstromError::Abort(_) => 14,
+            MaelstromError::KeyDoesNotExist(_) => 20,
+            MaelstromError::KeyAlreadyExists(_) => 21,
+            MaelstromError::PreconditionFailed(_) => 22,
+            MaelstromError::TxnConflict(_) => 30,
+            MaelstromError::Custom { code, .. } => *code,
         }
     }
 
+    /// Whether the operation that failed definitely did not take place.
+    ///
+    /// Indefinite errors (`timeout`, `crash` and custom codes) mean the operation may or may
+    /// not have happened, so Maelstrom's checkers can't count it as failed.
     #[must_use]
-    pub fn to_json_error(&self, msg_id: MsgId) -> Value {
-        let e_type = "error";
-        let unexpected_error = json!(
-            {
-            "type": e_type,
-            "in_reply_to": msg_id,
-            "code": Self::Crash.code(),
-            "text": Self::Crash.to_string(),
-            }
-        );
-        let body = MaelstromErrorBody::new(e_type.into(), msg_id, self.code(), self.to_string());
-        serde_json::to_value(body).unwrap_or(unexpected_error)
+    pub fn definite(&self) -> bool {
+        matches!(self.code(), 1 | 10 | 11 | 12 | 14 | 20 | 21 | 22 | 30)
+    }
+
+    /// Builds the error for a code and text received in an error reply. Codes without their
+    /// own variant, like `crash`, are kept as [`Custom`](Self::Custom) with their text.
+    #[must_use]
+    pub fn from_code(code: ErrCode, text: String) -> Self {
+        match code {
+            0 => Self::Timeout(text),
+            1 => Self::NodeNotFound(text),
+            10 => Self::NotSupported(text),
+            11 => Self::TemporarilyUnavailable(text),
+            14 => Self::Abort(text),
+            20 => Self::KeyDoesNotExist(text),
+            21 => Self::KeyAlreadyExists(text),
+            22 => Self::PreconditionFailed(text),
+            30 => Self::TxnConflict(text),
+            code => Self::Custom { code, text },
+        }
+    }
+
+    /// Builds the error reply sent from `src` to `dest` for the request with the `in_reply_to`
+    /// message ID.
+    #[must_use]
+    pub fn to_error_reply(
+        &self,
+        src: String,
+        dest: String,
+        in_reply_to: Option<MsgId>,
+    ) -> Message<MaelstromErrorBody> {
+        let body = MaelstromErrorBody::new("error".into(), self.code(), self.to_string());
+        Message::new(src, dest, Body::new(None, in_reply_to, body))
     }
 }
 
-#[derive(Serialize, Deserialize, Constructor, Debug)]
+#[derive(Serialize, Deserialize, Constructor, Clone, Debug, Eq, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub struct MaelstromErrorBody {
     #[serde(rename = "type")]
     e_type: String,
-    in_reply_to: MsgId,
     code: ErrCode,
     text: String,
 }
+
+impl MaelstromErrorBody {
+    #[must_use]
+    pub fn code(&self) -> ErrCode {
+        self.code
+    }
+
+    #[must_use]
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+impl From<MaelstromErrorBody> for MaelstromError {
+    fn from(body: MaelstromErrorBody) -> Self {
+        Self::from_code(body.code, body.text)
+    }
+}
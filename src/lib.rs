@@ -1,8 +1,8 @@
 // #![feature(associated_type_defaults)]
 #![deny(clippy::all)]
-#![deny(clippy::all)]
 #![warn(clippy::pedantic)]
 #![allow(clippy::missing_errors_doc)]
 pub mod error;
 pub mod message;
 pub mod server;
+pub mod services;
@@ -1,19 +1,27 @@
 use crate::{
     error::MaelstromError::{self, SerdeJsonError},
-    message::{self, build_reply, WorkloadHandler},
-    server::stdio::{BroadcastContext, NumericMessage, SharedIoServerContext},
+    message::{self, build_reply, HandlerFuture, RawRequest, WorkloadHandler},
+    server::{
+        rpc::{RpcClient, RpcPolicy},
+        schedule::PeriodicTask,
+        stdio::{IoServerContext, NumericMessage, SharedIoServerContext},
+    },
 };
 use derive_more::{Constructor, From};
+use futures::FutureExt;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
 use std::{
     collections::{HashMap, HashSet},
     fmt::Debug,
+    time::Duration,
 };
 
 pub type Request = message::Request<RequestBody>;
 pub type Response = message::Response<ResponseBody>;
 
+pub type MessageList = HashSet<NumericMessage>;
+pub type NodeMessages = HashMap<String, MessageList>;
+
 #[derive(Deserialize, Serialize, From, Clone, Debug, Eq, PartialEq)]
 #[serde(rename_all = "snake_case", tag = "type")]
 pub enum RequestBody {
@@ -28,6 +36,7 @@ pub enum RequestBody {
 pub enum ResponseBody {
     BroadcastOk,
     ReadOk(ReadOkBody),
+    SyncOk,
     TopologyOk,
 }
 
@@ -61,80 +70,198 @@ pub struct SyncOkBody {
     messages: Vec<NumericMessage>,
 }
 
+/// Messages seen by the node, and the ones its neighbors are known to have.
+#[derive(Debug, Clone, Default)]
+pub struct BroadcastState {
+    messages_saved: MessageList,
+    messages_neighbors_have: NodeMessages,
+    gossip_in_flight: HashSet<String>,
+}
+
+impl BroadcastState {
+    pub fn synced(&mut self, neighbors: &[String], node: String, messages: MessageList) {
+        self.messages_saved.extend(messages.iter().copied());
+        if neighbors.contains(&node) {
+            self.neighbor_has(node, messages);
+        }
+    }
+
+    pub fn neighbor_has(
+        &mut self,
+        node: String,
+        messages: impl IntoIterator<Item = NumericMessage>,
+    ) {
+        self.messages_neighbors_have
+            .entry(node)
+            .or_default()
+            .extend(messages);
+    }
+
+    /// Returns the messages to gossip to each neighbor, i.e. the ones it isn't known to have.
+    /// Neighbors that are still acknowledging the previous gossip are skipped.
+    fn gossip_batches(&mut self, neighbors: &[String]) -> Vec<(String, Vec<NumericMessage>)> {
+        let mut batches = Vec::new();
+        for node in neighbors {
+            if self.gossip_in_flight.contains(node) {
+                continue;
+            }
+            let messages_node_has = self
+                .messages_neighbors_have
+                .entry(node.clone())
+                .or_default();
+            let mut missing_messages = self
+                .messages_saved
+                .difference(messages_node_has)
+                .copied()
+                .collect::<Vec<_>>();
+            if !missing_messages.is_empty() {
+                missing_messages.sort_unstable();
+                self.gossip_in_flight.insert(node.clone());
+                batches.push((node.clone(), missing_messages));
+            }
+        }
+        batches
+    }
+}
+
+pub trait BroadcastContext {
+    fn add_message(&mut self, source: String, message: NumericMessage);
+    #[must_use]
+    fn messages(&self) -> Vec<NumericMessage>;
+    fn synced(&mut self, node: String, messages: MessageList);
+}
+
+impl BroadcastContext for IoServerContext {
+    fn add_message(&mut self, source: String, message: NumericMessage) {
+        self.synced(source, HashSet::from([message]));
+    }
+
+    fn messages(&self) -> Vec<NumericMessage> {
+        let mut list: Vec<NumericMessage> = self
+            .workload_state::<BroadcastState>()
+            .map(|state| state.messages_saved.iter().copied().collect())
+            .unwrap_or_default();
+        list.sort_unstable();
+        list
+    }
+
+    fn synced(&mut self, node: String, messages: MessageList) {
+        let neighbors = self.neighbors().clone();
+        self.workload_state_mut::<BroadcastState>()
+            .synced(&neighbors, node, messages);
+    }
+}
+
 pub struct Handler;
 
 impl WorkloadHandler for Handler {
-    fn response(context: SharedIoServerContext, req: Value) -> Result<String, MaelstromError> {
-        let req = Request::new(serde_json::from_value(req)?);
-        let ctx = &context;
-        let source = req.0.src.clone();
-        let body = match req.0.body.content.clone() {
-            RequestBody::Broadcast(body) => Self::process_broadcast(&context, source, &body),
-            RequestBody::Read => Self::process_read(&context),
-            RequestBody::Topology(body) => Self::process_topology(&context, &body),
-            RequestBody::Sync(body) => {
-                let _ = Self::process_sync(&context, source, body.messages);
-                return Ok(String::new());
-            }
-        }?;
+    fn response(&self, context: SharedIoServerContext, req: RawRequest) -> HandlerFuture {
+        async move {
+            let req: Request = req.deserialize()?;
+            let source = req.0.src.clone();
+            let body = match req.0.body.content.clone() {
+                RequestBody::Broadcast(body) => {
+                    Self::process_broadcast(&context, source, &body).await
+                }
+                RequestBody::Read => Self::process_read(&context).await,
+                RequestBody::Topology(body) => Self::process_topology(&context, &body).await,
+                RequestBody::Sync(body) => {
+                    Self::process_sync(&context, source, body.messages).await
+                }
+            }?;
 
-        let response = build_reply(req, ctx, body);
-        serde_json::to_string(&response).map_err(SerdeJsonError)
+            let response = build_reply(req, &context, body).await?;
+            serde_json::to_string(&response).map_err(SerdeJsonError)
+        }
+        .boxed()
     }
 }
 
 impl Handler {
-    pub fn process_broadcast(
+    pub async fn process_broadcast(
         context: &SharedIoServerContext,
         source: String,
         body: &Body,
     ) -> Result<ResponseBody, MaelstromError> {
+        let message = body.message;
         context
-            .write()
-            .map(|mut ctx| ctx.add_message(source, body.message))
-            .map_err(|e| MaelstromError::PoisonError(e.to_string()))?;
+            .call(move |ctx| ctx.add_message(source, message))
+            .await?;
         Ok(ResponseBody::BroadcastOk)
     }
-    pub fn process_read(context: &SharedIoServerContext) -> Result<ResponseBody, MaelstromError> {
-        let response = context
-            .write()
-            .map(|ctx| {
-                let messages = ctx.clone().messages();
-                ResponseBody::ReadOk(ReadOkBody { messages })
-            })
-            .map_err(|e| MaelstromError::PoisonError(e.to_string()))?;
-        Ok(response)
+
+    pub async fn process_read(
+        context: &SharedIoServerContext,
+    ) -> Result<ResponseBody, MaelstromError> {
+        let messages = context.call(|ctx| ctx.messages()).await?;
+        Ok(ResponseBody::ReadOk(ReadOkBody { messages }))
     }
 
-    pub fn process_sync(
+    pub async fn process_sync(
         context: &SharedIoServerContext,
         source: String,
         messages: Vec<NumericMessage>,
     ) -> Result<ResponseBody, MaelstromError> {
-        let response = context
-            .write()
-            .map(|mut ctx| {
-                let _queued = ctx.synced(source, HashSet::from_iter(messages));
-                ResponseBody::BroadcastOk
-            })
-            .map_err(|e| MaelstromError::PoisonError(e.to_string()))?;
-        Ok(response)
+        context
+            .call(move |ctx| ctx.synced(source, HashSet::from_iter(messages)))
+            .await?;
+        Ok(ResponseBody::SyncOk)
     }
 
-    pub fn process_topology(
+    pub async fn process_topology(
         context: &SharedIoServerContext,
         body: &TopologyBody,
     ) -> Result<ResponseBody, MaelstromError> {
+        let topology = body.topology.clone();
         context
-            .write()
-            .map(|mut ctx| {
-                let node_id = ctx.node();
-                let topology = body.topology.get(node_id);
-                if let Some(nodes) = topology {
+            .call(move |ctx| {
+                if let Some(nodes) = topology.get(ctx.node()) {
                     ctx.set_neighbors(nodes);
-                };
+                }
             })
-            .map_err(|e| MaelstromError::PoisonError(e.to_string()))?;
+            .await?;
         Ok(ResponseBody::TopologyOk)
     }
 }
+
+/// Gossips the messages each neighbor is missing every 125ms, and once more on shutdown.
+#[must_use]
+pub fn gossip_task() -> PeriodicTask {
+    PeriodicTask::new(Duration::from_millis(125), |context| async move {
+        gossip(&context).await
+    })
+    .run_on_shutdown(true)
+}
+
+/// Sends each neighbor a `sync` RPC with the messages it's missing. The messages are only
+/// known to be on the neighbor once it acknowledged them with `sync_ok`, so the ones that
+/// weren't acknowledged are sent again in the next round.
+pub async fn gossip(context: &SharedIoServerContext) -> Result<(), MaelstromError> {
+    let batches = context
+        .call(|ctx| {
+            let neighbors = ctx.neighbors().clone();
+            ctx.workload_state_mut::<BroadcastState>()
+                .gossip_batches(&neighbors)
+        })
+        .await?;
+
+    let policy = RpcPolicy::default()
+        .timeout(Duration::from_millis(500))
+        .max_attempts(3);
+    for (node, messages) in batches {
+        let body = RequestBody::Sync(SyncBody::new(messages.clone()));
+        let call = context.rpc_with(node.clone(), body, &policy);
+        let context = context.clone();
+        tokio::spawn(async move {
+            let acknowledged = call.await.is_ok();
+            let _ = context.cast(move |ctx| {
+                let state = ctx.workload_state_mut::<BroadcastState>();
+                state.gossip_in_flight.remove(&node);
+                if acknowledged {
+                    state.neighbor_has(node, messages);
+                }
+            });
+        });
+    }
+    Ok(())
+}
@@ -1,9 +1,10 @@
 use crate::{
-    error::MaelstromError::{self, SerdeJsonError},
-    message::{self, build_reply, WorkloadHandler},
+    error::MaelstromError::SerdeJsonError,
+    message::{self, build_reply, HandlerFuture, RawRequest, WorkloadHandler},
     server::stdio::SharedIoServerContext,
 };
 use derive_more::{Constructor, From};
+use futures::FutureExt;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::fmt::Debug;
@@ -31,11 +32,14 @@ pub struct Body {
 pub struct Handler;
 
 impl WorkloadHandler for Handler {
-    fn response(context: SharedIoServerContext, req: Value) -> Result<String, MaelstromError> {
-        let req = Request::new(serde_json::from_value(req)?);
-        let RequestBody::Echo(echo) = req.content().clone();
+    fn response(&self, context: SharedIoServerContext, req: RawRequest) -> HandlerFuture {
+        async move {
+            let req: Request = req.deserialize()?;
+            let RequestBody::Echo(echo) = req.content().clone();
 
-        let response = build_reply(req, &context, ResponseBody::EchoOk(echo));
-        serde_json::to_string(&response).map_err(SerdeJsonError)
+            let response = build_reply(req, &context, ResponseBody::EchoOk(echo)).await?;
+            serde_json::to_string(&response).map_err(SerdeJsonError)
+        }
+        .boxed()
     }
 }
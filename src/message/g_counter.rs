@@ -1,16 +1,23 @@
 use crate::{
-    error::MaelstromError::{self},
-    message::{self, build_reply, WorkloadHandler},
-    server::stdio::{GCounterContext, NodeCounters, NumericMessage, SharedIoServerContext},
+    error::MaelstromError::{self, KeyDoesNotExist, NotSupported, TemporarilyUnavailable},
+    message::{self, build_reply, send_request, HandlerFuture, RawRequest, WorkloadHandler},
+    server::{
+        schedule::PeriodicTask,
+        stdio::{IoServerContext, NumericMessage, SharedIoServerContext},
+    },
+    services::kv::{KvClient, UpdateOutcome},
 };
 use derive_more::{Constructor, From};
+use futures::FutureExt;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::fmt::Debug;
+use std::{collections::HashMap, fmt::Debug, time::Duration};
+use uuid::Uuid;
 
 pub type Request = message::Request<RequestBody>;
 pub type Response = message::Response<ResponseBody>;
 
+pub type NodeCounters = HashMap<String, usize>;
+
 #[derive(Deserialize, Serialize, From, Clone, Debug, Eq, PartialEq)]
 #[serde(rename_all = "snake_case", tag = "type")]
 pub enum RequestBody {
@@ -41,64 +48,238 @@ pub struct ReadOkBody {
     value: NumericMessage,
 }
 
+/// The node's counter, and the last counters it got from the other nodes.
+#[derive(Debug, Clone, Default)]
+pub struct GCounterState {
+    counter: usize,
+    node_counters: NodeCounters,
+}
+
+pub trait GCounterContext {
+    fn add_node_counter(&mut self, node: String, counter: NumericMessage);
+    fn cas_local_node_counter(&mut self, value: NumericMessage) -> usize;
+    fn counter(&self) -> NumericMessage;
+    fn max_node_counter(&mut self) -> usize;
+    fn node_counters(&self) -> NodeCounters;
+    fn set_counter(&mut self, value: NumericMessage);
+    fn update_all_node_counters(&mut self, new_counters: NodeCounters);
+    fn update_local_node_counter(&mut self, delta: NumericMessage);
+}
+
+impl GCounterContext for IoServerContext {
+    fn add_node_counter(&mut self, node: String, counter: NumericMessage) {
+        self.workload_state_mut::<GCounterState>()
+            .node_counters
+            .entry(node.clone())
+            .and_modify(|v| *v += counter)
+            .or_insert(counter);
+
+        let max_counter = self.max_node_counter();
+        self.cas_local_node_counter(counter.max(max_counter));
+    }
+
+    fn cas_local_node_counter(&mut self, value: NumericMessage) -> usize {
+        let state = self.workload_state_mut::<GCounterState>();
+        state.counter = state.counter.max(value);
+        state.counter
+    }
+
+    fn counter(&self) -> NumericMessage {
+        self.workload_state::<GCounterState>()
+            .map_or(0, |state| state.counter)
+    }
+
+    fn max_node_counter(&mut self) -> usize {
+        self.workload_state_mut::<GCounterState>()
+            .node_counters
+            .values()
+            .sum::<usize>()
+    }
+
+    fn node_counters(&self) -> NodeCounters {
+        self.workload_state::<GCounterState>()
+            .map(|state| state.node_counters.clone())
+            .unwrap_or_default()
+    }
+
+    fn set_counter(&mut self, value: NumericMessage) {
+        self.workload_state_mut::<GCounterState>().counter = value;
+    }
+
+    fn update_all_node_counters(&mut self, new_counters: NodeCounters) {
+        let node = self.node().clone();
+        let state = self.workload_state_mut::<GCounterState>();
+        for (key, value) in new_counters {
+            // Replace all node counter except self
+            if key != node {
+                *state.node_counters.entry(key.clone()).or_default() = value;
+            }
+        }
+    }
+
+    fn update_local_node_counter(&mut self, delta: NumericMessage) {
+        self.workload_state_mut::<GCounterState>().counter += delta;
+    }
+}
+
 pub struct Handler;
 
 impl WorkloadHandler for Handler {
-    fn response(context: SharedIoServerContext, req: Value) -> Result<String, MaelstromError> {
-        let req = Request::new(serde_json::from_value(req)?);
-        let ctx = context.clone();
-        let body = match req.0.body.content.clone() {
-            RequestBody::Add(body) => Self::process_add(&context, &body),
-            RequestBody::Read => Self::process_read(&context),
-            RequestBody::SyncCounter(body) => return Self::process_sync(&context, body.messages),
-        }?;
+    fn response(&self, context: SharedIoServerContext, req: RawRequest) -> HandlerFuture {
+        async move {
+            let req: Request = req.deserialize()?;
+            let body = match req.0.body.content.clone() {
+                RequestBody::Add(body) => Self::process_add(&context, &body).await,
+                RequestBody::Read => Self::process_read(&context).await,
+                RequestBody::SyncCounter(body) => {
+                    return Self::process_sync(&context, body.messages).await
+                }
+            }?;
 
-        build_reply(req, &ctx, body).serde_to_string()
+            build_reply(req, &context, body).await?.serde_to_string()
+        }
+        .boxed()
     }
 }
 
 impl Handler {
-    pub fn process_add(
+    pub async fn process_add(
         context: &SharedIoServerContext,
         body: &AddBody,
     ) -> Result<ResponseBody, MaelstromError> {
+        let delta = body.delta;
         context
-            .write()
-            .map(|mut ctx| {
-                ctx.update_local_node_counter(body.delta);
-                let node = ctx.node().to_string();
-                if body.delta > 0 {
-                    ctx.add_node_counter(node, body.delta);
+            .call(move |ctx| {
+                ctx.update_local_node_counter(delta);
+                let node = ctx.node().clone();
+                if delta > 0 {
+                    ctx.add_node_counter(node, delta);
                 }
             })
-            .map_err(|e| MaelstromError::PoisonError(e.to_string()))?;
-
+            .await?;
         Ok(ResponseBody::AddOk)
     }
 
-    pub fn process_read(context: &SharedIoServerContext) -> Result<ResponseBody, MaelstromError> {
-        let response = context
-            .read()
-            .map(|ctx| {
-                let value = ctx.counter();
-                ResponseBody::ReadOk(ReadOkBody { value })
-            })
-            .map_err(|e| MaelstromError::PoisonError(e.to_string()))?;
-        Ok(response)
+    pub async fn process_read(
+        context: &SharedIoServerContext,
+    ) -> Result<ResponseBody, MaelstromError> {
+        let value = context.call(|ctx| ctx.counter()).await?;
+        Ok(ResponseBody::ReadOk(ReadOkBody { value }))
     }
 
-    pub fn process_sync(
+    pub async fn process_sync(
         context: &SharedIoServerContext,
         new_counters: NodeCounters,
     ) -> Result<String, MaelstromError> {
         context
-            .write()
-            .map(|mut ctx| {
+            .call(move |ctx| {
                 ctx.update_all_node_counters(new_counters);
                 let max_neighbor_counter = ctx.max_node_counter();
                 let _ = ctx.cas_local_node_counter(max_neighbor_counter);
             })
-            .map_err(|e| MaelstromError::PoisonError(e.to_string()))?;
+            .await?;
         Ok(String::new())
     }
 }
+
+/// Sends the node counters to the neighbors every second.
+#[must_use]
+pub fn counter_sync_task() -> PeriodicTask {
+    PeriodicTask::new(Duration::from_secs(1), |context| async move {
+        deliver_counters(&context).await
+    })
+}
+
+pub async fn deliver_counters(context: &SharedIoServerContext) -> Result<(), MaelstromError> {
+    let (neighbors, counters) = context
+        .call(|ctx| (ctx.neighbors().clone(), ctx.node_counters()))
+        .await?;
+
+    // Don't try delivering counter if there are none
+    if counters.is_empty() {
+        return Ok(());
+    }
+
+    for node in neighbors {
+        let message = send_request(
+            node,
+            context,
+            RequestBody::SyncCounter(counters.clone().into()),
+        )
+        .await?;
+
+        context.outbound().send(message.serde_to_string()?)?;
+    }
+    Ok(())
+}
+
+/// The key of the counter in `seq-kv`, see [`SeqKvHandler`].
+pub const COUNTER_KEY: &str = "g-counter";
+
+/// G-counter storing the counter in `seq-kv`, instead of gossiping the node counters.
+///
+/// Adds read the counter and `cas` it to the new value, until no other add changed it in the
+/// meantime (see [`KvClient::update`]). Since `seq-kv` can serve stale reads, each `read`
+/// request is served after a write of a unique value, after which the node sees every write
+/// made before it.
+///
+/// Nothing is kept on the node, so a partitioned node fails its requests until it reaches
+/// `seq-kv` again instead of serving a diverging counter. This costs 2 round trips to `seq-kv`
+/// per add (and 2 more per conflict), and 2 per read, while the gossip version costs none on
+/// requests, but a `sync_counter` per neighbor every second.
+pub struct SeqKvHandler;
+
+impl WorkloadHandler for SeqKvHandler {
+    fn response(&self, context: SharedIoServerContext, req: RawRequest) -> HandlerFuture {
+        async move {
+            let req: Request = req.deserialize()?;
+            let kv = KvClient::seq_kv(context.clone());
+            let body = match &req.0.body.content {
+                RequestBody::Add(body) => Self::process_add(&kv, body).await,
+                RequestBody::Read => Self::process_read(&kv).await,
+                RequestBody::SyncCounter(_) => Err(NotSupported(
+                    "counters aren't synced when stored in seq-kv".into(),
+                )),
+            }?;
+
+            build_reply(req, &context, body).await?.serde_to_string()
+        }
+        .boxed()
+    }
+}
+
+impl SeqKvHandler {
+    pub async fn process_add(
+        kv: &KvClient,
+        body: &AddBody,
+    ) -> Result<ResponseBody, MaelstromError> {
+        let delta = body.delta;
+        if delta == 0 {
+            return Ok(ResponseBody::AddOk);
+        }
+        match kv.update_or(&COUNTER_KEY, 0, |value| value + delta).await? {
+            UpdateOutcome::Applied(_) => Ok(ResponseBody::AddOk),
+            UpdateOutcome::Conflicted => {
+                Err(TemporarilyUnavailable("too many concurrent adds".into()))
+            }
+        }
+    }
+
+    pub async fn process_read(kv: &KvClient) -> Result<ResponseBody, MaelstromError> {
+        let value = Self::fresh_read(kv).await?;
+        Ok(ResponseBody::ReadOk(ReadOkBody { value }))
+    }
+
+    /// Reads the latest value of the counter, which is 0 until the first add.
+    async fn fresh_read(kv: &KvClient) -> Result<NumericMessage, MaelstromError> {
+        // Overwriting one key per node is enough to move the node's view forward, without
+        // adding a key to the store on every read
+        let node = kv.context().call(|ctx| ctx.node().clone()).await?;
+        let sync_key = format!("{COUNTER_KEY}-sync-{node}");
+        kv.write(&sync_key, &Uuid::new_v4()).await?;
+        match kv.read(&COUNTER_KEY).await {
+            Err(KeyDoesNotExist(_)) => Ok(0),
+            result => result,
+        }
+    }
+}
@@ -1,12 +1,12 @@
 use crate::{
-    error::MaelstromError::{self, SerdeJsonError},
+    error::MaelstromError::SerdeJsonError,
     message,
-    message::{build_reply, WorkloadHandler},
+    message::{build_reply, HandlerFuture, RawRequest, WorkloadHandler},
     server::stdio::SharedIoServerContext,
 };
 use derive_more::{Constructor, From};
+use futures::FutureExt;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
 use uuid::Uuid;
 
 pub type Request = message::Request<RequestBody>;
@@ -32,10 +32,14 @@ pub struct Body {
 pub struct Handler;
 
 impl WorkloadHandler for Handler {
-    fn response(context: SharedIoServerContext, req: Value) -> Result<String, MaelstromError> {
-        let req: Request = Request::new(serde_json::from_value(req)?);
-        let id = Uuid::new_v4();
-        let response = build_reply(req, &context, ResponseBody::GenerateOk(Body::new(id)));
-        serde_json::to_string(&response).map_err(SerdeJsonError)
+    fn response(&self, context: SharedIoServerContext, req: RawRequest) -> HandlerFuture {
+        async move {
+            let req: Request = req.deserialize()?;
+            let id = Uuid::new_v4();
+            let response =
+                build_reply(req, &context, ResponseBody::GenerateOk(Body::new(id))).await?;
+            serde_json::to_string(&response).map_err(SerdeJsonError)
+        }
+        .boxed()
     }
 }
@@ -1,12 +1,12 @@
 use crate::{
-    error::MaelstromError::{self, SerdeJsonError},
+    error::MaelstromError::SerdeJsonError,
     message,
-    message::{build_reply, WorkloadHandler},
+    message::{build_reply, HandlerFuture, RawRequest, WorkloadHandler},
     server::stdio::SharedIoServerContext,
 };
 use derive_more::{Constructor, From};
+use futures::FutureExt;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
 
 pub type Request = message::Request<RequestBody>;
 pub type Response = message::Response<ResponseBody>;
@@ -32,15 +32,18 @@ pub struct Body {
 pub struct Handler;
 
 impl WorkloadHandler for Handler {
-    fn response(context: SharedIoServerContext, req: Value) -> Result<String, MaelstromError> {
-        let req = Request::new(serde_json::from_value(req)?);
-        let _ = context.write().map(|mut c| {
-            c.set_node(req.0.dest.clone());
+    fn response(&self, context: SharedIoServerContext, req: RawRequest) -> HandlerFuture {
+        async move {
+            let req: Request = req.deserialize()?;
             let RequestBody::Init(neighbors) = req.0.body.content.clone();
-            c.set_neighbors(neighbors.node_ids.as_slice());
-        });
+            let node = req.0.dest.clone();
+            context
+                .call(move |ctx| ctx.initialize(node, neighbors.node_ids.as_slice()))
+                .await??;
 
-        let response = build_reply(req, &context, ResponseBody::InitOk);
-        serde_json::to_string(&response).map_err(SerdeJsonError)
+            let response = build_reply(req, &context, ResponseBody::InitOk).await?;
+            serde_json::to_string(&response).map_err(SerdeJsonError)
+        }
+        .boxed()
     }
 }
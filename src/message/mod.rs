@@ -1,8 +1,18 @@
 use crate::{error::MaelstromError, server::stdio::SharedIoServerContext};
 use derive_more::{Constructor, From};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use futures::future::{BoxFuture, FutureExt};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Deserialize, Serialize,
+};
 use serde_json::{Error, Value};
-use std::str::FromStr;
+use std::{
+    borrow::Cow,
+    fmt::{Debug, Display, Formatter},
+    future::Future,
+    str::FromStr,
+    sync::Arc,
+};
 
 pub mod broadcast;
 pub mod echo;
@@ -32,6 +42,121 @@ pub struct Body<T> {
 #[derive(Deserialize, Serialize, Constructor, From, Clone, Debug, Eq, PartialEq)]
 pub struct Request<T: Serialize>(Message<T>);
 
+/// A message as read from the input, with the envelope fields needed to route it.
+///
+/// Only the envelope is decoded when the message is read, so handlers can deserialize their
+/// typed request straight from the raw text with [`RawRequest::deserialize`]. The raw text is
+/// shared by the clones of a request.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RawRequest {
+    raw: Arc<String>,
+    src: String,
+    dest: String,
+    msg_type: Option<RequestType>,
+    workload: Option<String>,
+    msg_id: Option<MsgId>,
+    in_reply_to: Option<MsgId>,
+}
+
+/// The envelope fields of a message, borrowed from its raw text. The rest of the body is
+/// skipped without being decoded.
+#[derive(Deserialize)]
+struct Envelope<'a> {
+    #[serde(borrow)]
+    src: Cow<'a, str>,
+    #[serde(borrow)]
+    dest: Cow<'a, str>,
+    #[serde(borrow)]
+    body: EnvelopeBody<'a>,
+}
+
+#[derive(Deserialize)]
+struct EnvelopeBody<'a> {
+    #[serde(rename = "type", borrow, default)]
+    msg_type: Option<Cow<'a, str>>,
+    #[serde(borrow, default)]
+    workload: Option<WorkloadField<'a>>,
+    msg_id: Option<MsgId>,
+    in_reply_to: Option<MsgId>,
+}
+
+/// The `workload` a message is addressed to. Values that aren't a name are ignored, as they
+/// were before messages could name a workload.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WorkloadField<'a> {
+    Name(#[serde(borrow)] Cow<'a, str>),
+    Other(IgnoredAny),
+}
+
+impl RawRequest {
+    pub fn parse(raw: String) -> Result<Self, MaelstromError> {
+        let envelope = serde_json::from_str::<Envelope>(&raw)?;
+        Ok(Self {
+            src: envelope.src.into_owned(),
+            dest: envelope.dest.into_owned(),
+            msg_type: envelope.body.msg_type.map(RequestType::parse),
+            workload: match envelope.body.workload {
+                Some(WorkloadField::Name(workload)) => Some(workload.into_owned()),
+                Some(WorkloadField::Other(_)) | None => None,
+            },
+            msg_id: envelope.body.msg_id,
+            in_reply_to: envelope.body.in_reply_to,
+            raw: Arc::new(raw),
+        })
+    }
+
+    /// Deserializes the whole message, e.g. into a typed [`Request`], from its raw text.
+    pub fn deserialize<'a, T: Deserialize<'a>>(&'a self) -> Result<T, MaelstromError> {
+        serde_json::from_str(&self.raw).map_err(MaelstromError::SerdeJsonError)
+    }
+
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+
+    #[must_use]
+    pub fn src(&self) -> &str {
+        &self.src
+    }
+
+    #[must_use]
+    pub fn dest(&self) -> &str {
+        &self.dest
+    }
+
+    #[must_use]
+    pub fn msg_type(&self) -> Option<&RequestType> {
+        self.msg_type.as_ref()
+    }
+
+    /// The workload the request is addressed to, when a node serves several that handle the
+    /// same request type (see [`IoServer::workload`](crate::server::stdio::IoServer::workload)).
+    #[must_use]
+    pub fn workload(&self) -> Option<&str> {
+        self.workload.as_deref()
+    }
+
+    #[must_use]
+    pub fn msg_id(&self) -> Option<MsgId> {
+        self.msg_id
+    }
+
+    #[must_use]
+    pub fn in_reply_to(&self) -> Option<MsgId> {
+        self.in_reply_to
+    }
+}
+
+impl FromStr for RawRequest {
+    type Err = MaelstromError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s.to_owned())
+    }
+}
+
 #[derive(Deserialize, Serialize, Constructor, From, Clone, Debug, Eq, PartialEq)]
 pub struct Response<T: Serialize>(Message<T>);
 
@@ -50,7 +175,7 @@ impl<T: Serialize> Response<T> {
         self.0.body.content = content;
     }
 
-    fn serde_to_string(&self) -> Result<String, MaelstromError> {
+    pub fn serde_to_string(&self) -> Result<String, MaelstromError> {
         serde_json::to_string(&self).map_err(MaelstromError::SerdeJsonError)
     }
 }
@@ -63,21 +188,22 @@ impl<T: Serialize + DeserializeOwned> FromStr for Message<T> {
     }
 }
 
-fn build_reply<T: Serialize, R: Serialize>(
+/// Builds the reply to `req` from the node, with a fresh `msg_id`.
+pub async fn build_reply<T: Serialize, R: Serialize>(
     req: Request<T>,
     ctx: &SharedIoServerContext,
     content: R,
-) -> Response<R> {
-    let mut ctx = ctx.write().unwrap();
-    let node_id = ctx.node().to_string();
-    let msg_id = ctx.next_msg_id();
+) -> Result<Response<R>, MaelstromError> {
+    let (node_id, msg_id) = ctx
+        .call(|ctx| (ctx.node().clone(), ctx.next_msg_id()))
+        .await?;
     let dest = if req.0.src == node_id {
         req.0.dest
     } else {
         req.0.src
     };
 
-    Response(Message {
+    Ok(Response(Message {
         src: node_id,
         dest,
         body: Body {
@@ -85,25 +211,19 @@ fn build_reply<T: Serialize, R: Serialize>(
             in_reply_to: req.0.body.msg_id,
             content,
         },
-    })
+    }))
 }
 
-/// Sends a custom workload request.
-///
-/// # Panics
-///
-/// Might panic if the write lock is already held by the current thread.
-pub fn send_request<T: Serialize>(
+/// Builds a custom workload request from the node to `dest`, with a fresh `msg_id`.
+pub async fn send_request<T: Serialize>(
     dest: String,
     ctx: &SharedIoServerContext,
     content: T,
-) -> Request<T> {
-    let mut ctx = ctx
-        .write()
-        .expect("Unable to write to STDOUT (lock failed)");
-    let src = ctx.node().to_string();
-    let msg_id = ctx.next_msg_id();
-    Request(Message {
+) -> Result<Request<T>, MaelstromError> {
+    let (src, msg_id) = ctx
+        .call(|ctx| (ctx.node().clone(), ctx.next_msg_id()))
+        .await?;
+    Ok(Request(Message {
         src,
         dest,
         body: Body {
@@ -111,7 +231,7 @@ pub fn send_request<T: Serialize>(
             in_reply_to: None,
             content,
         },
-    })
+    }))
 }
 
 impl<T: Serialize + DeserializeOwned> Message<T> {
@@ -139,22 +259,119 @@ where
     }
 }
 
-pub trait WorkloadHandler {
-    fn response(context: SharedIoServerContext, req: Value) -> Result<String, MaelstromError>;
+pub type HandlerFuture = BoxFuture<'static, Result<String, MaelstromError>>;
+
+/// An async handler for one or more workload request types.
+///
+/// Handlers are shared between requests, so any state they own must be `Send + Sync`.
+pub trait WorkloadHandler: Send + Sync + 'static {
+    fn response(&self, context: SharedIoServerContext, req: RawRequest) -> HandlerFuture;
+}
+
+impl Debug for dyn WorkloadHandler {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("WorkloadHandler")
+    }
+}
+
+impl<H: WorkloadHandler + ?Sized> WorkloadHandler for Arc<H> {
+    fn response(&self, context: SharedIoServerContext, req: RawRequest) -> HandlerFuture {
+        (**self).response(context, req)
+    }
+}
+
+/// Wraps an async closure so it can be registered as a [`WorkloadHandler`].
+#[derive(Clone, Debug)]
+pub struct HandlerFn<F> {
+    f: F,
 }
 
-#[derive(Deserialize, Serialize, Default, Debug, From, Clone, Eq, PartialEq, Hash)]
-#[serde(rename_all = "snake_case")]
-pub enum RequestTypes {
-    #[default]
-    Add,
-    Init,
-    Echo,
-    Generate,
-    Broadcast,
-    Read,
-    Topology,
-    Sync,
-    SyncCounter,
-    SyncOk,
+pub fn handler_fn<F, Fut>(f: F) -> HandlerFn<F>
+where
+    F: Fn(SharedIoServerContext, RawRequest) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<String, MaelstromError>> + Send + 'static,
+{
+    HandlerFn { f }
+}
+
+impl<F, Fut> WorkloadHandler for HandlerFn<F>
+where
+    F: Fn(SharedIoServerContext, RawRequest) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<String, MaelstromError>> + Send + 'static,
+{
+    fn response(&self, context: SharedIoServerContext, req: RawRequest) -> HandlerFuture {
+        (self.f)(context, req).boxed()
+    }
+}
+
+/// The `type` of a message body, used to pick the [`WorkloadHandler`] for a request.
+///
+/// Any string can be used, so downstream crates can register handlers for their own workloads.
+/// The types used by the built-in workloads are available as constants.
+#[derive(Deserialize, Serialize, Default, Debug, Clone, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct RequestType(Cow<'static, str>);
+
+impl RequestType {
+    pub const ADD: Self = Self::from_static("add");
+    pub const BROADCAST: Self = Self::from_static("broadcast");
+    pub const ECHO: Self = Self::from_static("echo");
+    pub const GENERATE: Self = Self::from_static("generate");
+    pub const INIT: Self = Self::from_static("init");
+    pub const READ: Self = Self::from_static("read");
+    pub const SYNC: Self = Self::from_static("sync");
+    pub const SYNC_COUNTER: Self = Self::from_static("sync_counter");
+    pub const SYNC_OK: Self = Self::from_static("sync_ok");
+    pub const TOPOLOGY: Self = Self::from_static("topology");
+
+    #[must_use]
+    pub const fn from_static(name: &'static str) -> Self {
+        Self(Cow::Borrowed(name))
+    }
+
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The type named `name`. Known types borrow their constant, so only the other types are
+    /// allocated.
+    fn parse(name: Cow<'_, str>) -> Self {
+        match name.as_ref() {
+            "add" => Self::ADD,
+            "broadcast" => Self::BROADCAST,
+            "echo" => Self::ECHO,
+            "generate" => Self::GENERATE,
+            "init" => Self::INIT,
+            "read" => Self::READ,
+            "sync" => Self::SYNC,
+            "sync_counter" => Self::SYNC_COUNTER,
+            "sync_ok" => Self::SYNC_OK,
+            "topology" => Self::TOPOLOGY,
+            _ => Self::from(name.into_owned()),
+        }
+    }
+
+    /// The type as registered for `workload`, e.g. `g-counter/read`.
+    #[must_use]
+    pub fn in_workload(&self, workload: &str) -> Self {
+        Self::from(format!("{workload}/{self}"))
+    }
+}
+
+impl From<&'static str> for RequestType {
+    fn from(name: &'static str) -> Self {
+        Self::from_static(name)
+    }
+}
+
+impl From<String> for RequestType {
+    fn from(name: String) -> Self {
+        Self(Cow::Owned(name))
+    }
+}
+
+impl Display for RequestType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
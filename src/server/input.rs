@@ -0,0 +1,51 @@
+use std::{
+    io::{BufRead, Result},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncBufRead, AsyncRead, ReadBuf};
+
+/// Adapts a blocking [`BufRead`] (e.g. a `Cursor` or a locked `Stdin`) so it can be used as the
+/// input of an [`IoServer`](crate::server::stdio::IoServer).
+///
+/// Every poll completes immediately by calling the blocking reader, so this should only be used
+/// for inputs that never wait on data, like in-memory test inputs.
+#[derive(Debug)]
+pub struct SyncBufReader<R> {
+    inner: R,
+}
+
+impl<R: BufRead> SyncBufReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: BufRead + Unpin> AsyncRead for SyncBufReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let inner = &mut self.get_mut().inner;
+        let available = inner.fill_buf()?;
+        let size = available.len().min(buf.remaining());
+        buf.put_slice(&available[..size]);
+        inner.consume(size);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: BufRead + Unpin> AsyncBufRead for SyncBufReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<&[u8]>> {
+        Poll::Ready(self.get_mut().inner.fill_buf())
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.get_mut().inner.consume(amt);
+    }
+}
@@ -1,2 +1,12 @@
+pub mod input;
+pub mod node;
+pub mod outbound;
+pub mod panic;
 pub mod router;
+pub mod rpc;
+pub mod schedule;
+pub mod shutdown;
+pub mod state;
 pub mod stdio;
+pub mod timer;
+pub mod transport;
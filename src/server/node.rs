@@ -0,0 +1,84 @@
+use crate::{
+    error::MaelstromError::{self, Crash, NodeStopped},
+    server::{outbound::Outbound, panic::panic_message, stdio::IoServerContext},
+};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+
+/// A change to the node's state, applied by the node's task.
+type Command = Box<dyn FnOnce(&mut IoServerContext) + Send>;
+
+/// Handle used by handlers, periodic tasks and RPCs to reach the state of their node.
+///
+/// The state is owned by a single task that applies the commands sent through its handles one
+/// at a time, in the order they were sent. Commands can't await, so the state is never held
+/// across an `.await` and there's no lock to poison or to take in the wrong order.
+#[derive(Clone, Debug)]
+pub struct NodeHandle {
+    commands: UnboundedSender<Command>,
+    outbound: Outbound,
+}
+
+impl NodeHandle {
+    /// Runs `f` on the node's state and resolves to its result.
+    pub async fn call<R, F>(&self, f: F) -> Result<R, MaelstromError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut IoServerContext) -> R + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        self.cast(move |ctx| {
+            let _ = sender.send(f(ctx));
+        })?;
+        // The result is only dropped if `f` panicked
+        receiver.await.map_err(|_| Crash)
+    }
+
+    /// Queues `f` to run on the node's state, without waiting for it.
+    pub fn cast<F>(&self, f: F) -> Result<(), MaelstromError>
+    where
+        F: FnOnce(&mut IoServerContext) + Send + 'static,
+    {
+        self.commands.send(Box::new(f)).map_err(|_| NodeStopped)
+    }
+
+    /// The node's outbound queue, which can be used without going through the node's task.
+    #[must_use]
+    pub fn outbound(&self) -> &Outbound {
+        &self.outbound
+    }
+}
+
+/// Owns the state of a node and applies the commands sent through its [`NodeHandle`]s.
+#[derive(Debug)]
+pub(crate) struct Node {
+    context: IoServerContext,
+    commands: UnboundedReceiver<Command>,
+}
+
+impl Node {
+    pub(crate) fn new(context: IoServerContext) -> (Self, NodeHandle) {
+        let (sender, commands) = unbounded_channel();
+        let handle = NodeHandle {
+            commands: sender,
+            outbound: context.outbound().clone(),
+        };
+        (Self { context, commands }, handle)
+    }
+
+    /// Applies commands until every handle is dropped.
+    ///
+    /// A command that panics only fails its own call: the state keeps the changes made before
+    /// the panic, and the next commands are applied as usual.
+    pub(crate) async fn run(mut self) {
+        while let Some(command) = self.commands.recv().await {
+            let context = &mut self.context;
+            if let Err(panic) = catch_unwind(AssertUnwindSafe(|| command(context))) {
+                eprintln!("Node command panicked: {}", panic_message(&panic));
+            }
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use crate::error::MaelstromError::{self, OutboundClosed};
+use futures::future::{ready, Ready};
+use std::{
+    collections::HashSet,
+    io::Write,
+    sync::{Arc, OnceLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc::{error::TryRecvError, unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::{self, Instant},
+};
+use tower::Service;
+
+pub type OutboundReceiver = UnboundedReceiver<OutboundMessage>;
+
+/// Handle used to queue every outbound message (replies, gossip and RPCs) for the server's writer.
+#[derive(Clone, Debug)]
+pub struct Outbound {
+    sender: UnboundedSender<OutboundMessage>,
+    /// The ids of the cluster's nodes, known once the node is initialized.
+    nodes: Arc<OnceLock<HashSet<String>>>,
+}
+
+/// A serialized message queued on an [`Outbound`], and whether it goes to a client.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OutboundMessage {
+    line: String,
+    to_client: bool,
+}
+
+impl OutboundMessage {
+    /// A message, sent to a client if `to_client`, see [`Outbound::is_client`].
+    #[must_use]
+    pub fn new(line: String, to_client: bool) -> Self {
+        Self { line, to_client }
+    }
+
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.line
+    }
+
+    #[must_use]
+    pub fn into_string(self) -> String {
+        self.line
+    }
+}
+
+impl Outbound {
+    #[must_use]
+    pub fn channel() -> (Self, OutboundReceiver) {
+        let (sender, receiver) = unbounded_channel();
+        let outbound = Self {
+            sender,
+            nodes: Arc::default(),
+        };
+        (outbound, receiver)
+    }
+
+    /// Records the ids of the cluster's nodes from `init`, telling them apart from clients.
+    pub(crate) fn set_nodes(&self, node_ids: &[String]) {
+        let _ = self.nodes.set(node_ids.iter().cloned().collect());
+    }
+
+    /// Whether `dest` is a client, i.e. isn't one of the nodes from `init`. Every destination
+    /// is a client until the node is initialized.
+    #[must_use]
+    pub fn is_client(&self, dest: &str) -> bool {
+        self.nodes.get().is_none_or(|nodes| !nodes.contains(dest))
+    }
+
+    /// Queues a message to another node or to a service.
+    pub fn send(&self, message: String) -> Result<(), MaelstromError> {
+        self.send_message(OutboundMessage {
+            line: message,
+            to_client: false,
+        })
+    }
+
+    /// Queues a message to `dest`, which may be a client.
+    pub fn send_to(&self, dest: &str, message: String) -> Result<(), MaelstromError> {
+        self.send_message(OutboundMessage::new(message, self.is_client(dest)))
+    }
+
+    pub fn send_message(&self, message: OutboundMessage) -> Result<(), MaelstromError> {
+        if message.line.is_empty() {
+            return Ok(());
+        }
+        self.sender.send(message).map_err(|_| OutboundClosed)
+    }
+}
+
+impl Default for Outbound {
+    /// An outbound handle that isn't attached to a writer. Sending on it fails with
+    /// [`OutboundClosed`].
+    fn default() -> Self {
+        Self::channel().0
+    }
+}
+
+impl Service<OutboundMessage> for Outbound {
+    type Response = String;
+    type Error = MaelstromError;
+    type Future = Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, message: OutboundMessage) -> Self::Future {
+        let line = message.line.clone();
+        ready(self.send_message(message).map(|()| line))
+    }
+}
+
+/// How queued outbound messages are coalesced before being written to the server's output.
+///
+/// Messages are written together, with a single flush, once the batch reaches `max_bytes` or
+/// `max_delay` after its first message. Replies to clients (anyone but the nodes from `init`) flush
+/// the batch right away by default, so batching only delays messages between nodes.
+#[derive(Clone, Copy, Debug)]
+pub struct OutputBatching {
+    max_bytes: usize,
+    max_delay: Duration,
+    flush_client_replies: bool,
+}
+
+impl Default for OutputBatching {
+    /// Batches of up to 16 KiB, held for at most 1 millisecond.
+    fn default() -> Self {
+        Self {
+            max_bytes: 16 * 1024,
+            max_delay: Duration::from_millis(1),
+            flush_client_replies: true,
+        }
+    }
+}
+
+impl OutputBatching {
+    /// Writes every message as soon as it's queued.
+    #[must_use]
+    pub fn disabled() -> Self {
+        Self {
+            max_bytes: 0,
+            max_delay: Duration::ZERO,
+            flush_client_replies: true,
+        }
+    }
+
+    #[must_use]
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    #[must_use]
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Sets whether a message sent to a client flushes its batch immediately (the default).
+    #[must_use]
+    pub fn flush_client_replies(mut self, enabled: bool) -> Self {
+        self.flush_client_replies = enabled;
+        self
+    }
+}
+
+/// Writes the messages queued on an [`Outbound`] in batches.
+///
+/// The pending batch is kept between calls, so a write cancelled while waiting for more
+/// messages doesn't lose the messages already taken from the queue.
+#[derive(Debug)]
+pub(crate) struct OutboundWriter {
+    receiver: OutboundReceiver,
+    batching: OutputBatching,
+    batch: Vec<u8>,
+    flush_now: bool,
+}
+
+impl OutboundWriter {
+    pub(crate) fn new(receiver: OutboundReceiver) -> Self {
+        Self {
+            receiver,
+            batching: OutputBatching::default(),
+            batch: Vec::new(),
+            flush_now: false,
+        }
+    }
+
+    pub(crate) fn set_batching(&mut self, batching: OutputBatching) {
+        self.batching = batching;
+    }
+
+    /// Waits for the next batch of messages. Returns `false` once the queue is closed and
+    /// there's nothing left to write.
+    pub(crate) async fn next_batch(&mut self) -> bool {
+        if self.batch.is_empty() {
+            match self.receiver.recv().await {
+                Some(message) => self.push(&message),
+                None => return false,
+            }
+        }
+        let deadline = Instant::now() + self.batching.max_delay;
+        while !self.is_full() {
+            // Take what's already queued without waiting on the timer
+            let message = match self.receiver.try_recv() {
+                Ok(message) => Some(message),
+                Err(TryRecvError::Empty) => time::timeout_at(deadline, self.receiver.recv())
+                    .await
+                    .ok()
+                    .flatten(),
+                Err(TryRecvError::Disconnected) => None,
+            };
+            let Some(message) = message else {
+                break;
+            };
+            self.push(&message);
+        }
+        true
+    }
+
+    /// Takes every message still queued, without waiting for more.
+    pub(crate) fn drain(&mut self) {
+        while let Ok(message) = self.receiver.try_recv() {
+            self.push(&message);
+        }
+    }
+
+    /// Writes the pending batch with a single flush.
+    pub(crate) fn write_to<O: Write>(&mut self, output: &mut O) -> Result<(), MaelstromError> {
+        self.flush_now = false;
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        output.write_all(&self.batch)?;
+        self.batch.clear();
+        output.flush()?;
+        Ok(())
+    }
+
+    fn push(&mut self, message: &OutboundMessage) {
+        if message.line.is_empty() {
+            return;
+        }
+        self.batch.extend_from_slice(message.line.as_bytes());
+        self.batch.push(b'\n');
+        if self.batching.flush_client_replies && message.to_client {
+            self.flush_now = true;
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.flush_now || self.batch.len() >= self.batching.max_bytes
+    }
+}
@@ -0,0 +1,106 @@
+use crate::{
+    error::MaelstromError::{self, Crash},
+    message::RawRequest,
+    server::{outbound::Outbound, router::ErrorReply},
+};
+use futures::future::{ready, BoxFuture, FutureExt};
+use std::{
+    any::Any,
+    panic::{catch_unwind, AssertUnwindSafe},
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+
+/// Tower layer turning a panic while handling a request into a `crash` error reply (code 13).
+///
+/// The panic is logged with the request that caused it, and only fails that request: the
+/// server keeps handling the next ones.
+#[derive(Clone, Debug)]
+pub struct CatchPanicLayer {
+    outbound: Outbound,
+}
+
+impl CatchPanicLayer {
+    /// Creates a layer sending the error replies through `outbound`.
+    #[must_use]
+    pub fn new(outbound: Outbound) -> Self {
+        Self { outbound }
+    }
+}
+
+impl<S> Layer<S> for CatchPanicLayer {
+    type Service = CatchPanicService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CatchPanicService {
+            inner,
+            outbound: self.outbound.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CatchPanicService<S> {
+    inner: S,
+    outbound: Outbound,
+}
+
+impl<S> Service<RawRequest> for CatchPanicService<S>
+where
+    S: Service<RawRequest, Response = String, Error = MaelstromError>,
+    S::Future: Send + 'static,
+{
+    type Response = String;
+    type Error = MaelstromError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: RawRequest) -> Self::Future {
+        let crashed = Crashed {
+            request: req.clone(),
+            outbound: self.outbound.clone(),
+        };
+        let response = match catch_unwind(AssertUnwindSafe(|| self.inner.call(req))) {
+            Ok(response) => response,
+            Err(panic) => return ready(crashed.reply(&panic)).boxed(),
+        };
+        async move {
+            match AssertUnwindSafe(response).catch_unwind().await {
+                Ok(result) => result,
+                Err(panic) => crashed.reply(&panic),
+            }
+        }
+        .boxed()
+    }
+}
+
+/// The request being handled, kept to report a panic while handling it.
+struct Crashed {
+    request: RawRequest,
+    outbound: Outbound,
+}
+
+impl Crashed {
+    fn reply(self, panic: &Box<dyn Any + Send>) -> Result<String, MaelstromError> {
+        eprintln!(
+            "Handler panicked: {}: {}",
+            panic_message(panic),
+            self.request.as_str().trim_end()
+        );
+        let reply = ErrorReply::new(&self.request, &self.outbound).build(&Crash)?;
+        self.outbound.send_message(reply.clone())?;
+        Ok(reply.into_string())
+    }
+}
+
+/// The message given to `panic!`, if it's a string.
+pub(crate) fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
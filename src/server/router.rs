@@ -1,13 +1,20 @@
 use crate::{
-    error::MaelstromError::{self, NoHandlerForRequestType, UnknownRequestType},
-    message::{Message, RequestTypes},
-    server::stdio::SharedIoServerContext,
+    error::MaelstromError::{
+        self, NodeAlreadyInitialized, NodeNotInitialized, NotSupported, SerdeJsonError,
+        UnknownRequestType,
+    },
+    message::{HandlerFuture, Message, MsgId, RawRequest, RequestType, WorkloadHandler},
+    server::{
+        outbound::{Outbound, OutboundMessage},
+        stdio::{NodeState, SharedIoServerContext},
+    },
 };
-use futures::future::{BoxFuture, FutureExt};
-use serde_json::{from_value, Value};
+use futures::future::{ready, BoxFuture, FutureExt};
+use serde_json::Value;
 use std::{
+    borrow::Cow,
     collections::HashMap,
-    str::FromStr,
+    future::Future,
     sync::Arc,
     task::{Context, Poll},
 };
@@ -21,6 +28,7 @@ pub struct RouterLayer {
 }
 
 impl RouterLayer {
+    #[must_use]
     pub fn new(context: SharedIoServerContext, handlers: Arc<HandlerMap>) -> Self {
         Self { context, handlers }
     }
@@ -28,7 +36,7 @@ impl RouterLayer {
 
 impl<S> Layer<S> for RouterLayer
 where
-    S: Service<String>,
+    S: Service<OutboundMessage>,
 {
     type Service = RouterService<S>;
 
@@ -43,8 +51,8 @@ where
     }
 }
 
-pub type HandlerFn = fn(SharedIoServerContext, Value) -> Result<String, MaelstromError>;
-pub type HandlerMap = HashMap<RequestTypes, HandlerFn>;
+pub type SharedHandler = Arc<dyn WorkloadHandler>;
+pub type HandlerMap = HashMap<RequestType, SharedHandler>;
 
 #[allow(clippy::module_name_repetitions)]
 #[derive(Clone)]
@@ -54,9 +62,9 @@ pub struct RouterService<S> {
     handlers: Arc<HandlerMap>,
 }
 
-impl<S> Service<String> for RouterService<S>
+impl<S> Service<RawRequest> for RouterService<S>
 where
-    S: Service<String, Response = String, Error = MaelstromError>,
+    S: Service<OutboundMessage, Response = String, Error = MaelstromError> + Clone + Send + 'static,
     S::Future: Send + 'static,
 {
     type Response = S::Response;
@@ -66,45 +74,134 @@ where
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, req: String) -> Self::Future {
-        match self.route_message_to_handler(&req) {
-            Ok(req) => self.inner.call(req).boxed(),
-            Err(e) => self.inner.call(e.to_json_error(1).to_string()).boxed(),
+    fn call(&mut self, req: RawRequest) -> Self::Future {
+        if req.in_reply_to().is_some() {
+            self.complete_rpc(&req);
+            return ready(Ok(String::new())).boxed();
         }
+
+        let mut inner = self.inner.clone();
+        let router = self.clone();
+        let error_reply = ErrorReply::new(&req, self.context.outbound());
+        async move {
+            match router.route_message_to_handler(req).await {
+                Ok(Some(response)) => {
+                    let response = match response.await {
+                        Ok(response) => error_reply.reply(response),
+                        Err(e) => error_reply.build(&e)?,
+                    };
+                    inner.call(response).await
+                }
+                Ok(None) => Ok(String::new()),
+                Err(e) => inner.call(error_reply.build(&e)?).await,
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Addresses the error reply for a request back to its sender.
+#[derive(Clone, Debug)]
+pub(crate) struct ErrorReply {
+    src: String,
+    dest: String,
+    in_reply_to: Option<MsgId>,
+    to_client: bool,
+}
+
+impl ErrorReply {
+    pub(crate) fn new(req: &RawRequest, outbound: &Outbound) -> Self {
+        Self {
+            src: req.dest().to_owned(),
+            dest: req.src().to_owned(),
+            in_reply_to: req.msg_id(),
+            to_client: outbound.is_client(req.src()),
+        }
+    }
+
+    pub(crate) fn build(self, e: &MaelstromError) -> Result<OutboundMessage, MaelstromError> {
+        // Internal messages (e.g. timer events) come from the node itself, so there's no one to
+        // reply to
+        if self.src == self.dest {
+            eprintln!("Unable to handle internal message: {e}");
+            return Ok(self.reply(String::new()));
+        }
+        let to_client = self.to_client;
+        let reply = e.to_error_reply(self.src, self.dest, self.in_reply_to);
+        let reply = serde_json::to_string(&reply).map_err(SerdeJsonError)?;
+        Ok(OutboundMessage::new(reply, to_client))
+    }
+
+    /// Addresses a handler's serialized reply back to the request's sender.
+    pub(crate) fn reply(&self, reply: String) -> OutboundMessage {
+        OutboundMessage::new(reply, self.to_client)
+    }
+}
+
+impl<S> RouterService<S>
+where
+    S: Service<OutboundMessage, Response = String, Error = MaelstromError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    /// Hands a reply to the RPC waiting for it, instead of routing it to a handler.
+    fn complete_rpc(&self, reply: &RawRequest) {
+        let reply = match reply.deserialize::<Message<Value>>() {
+            Ok(reply) => reply,
+            Err(e) => {
+                eprintln!("Unable to parse reply: {e}");
+                return;
+            }
+        };
+        let _ = self.context.cast(|ctx| {
+            if let Err(reply) = ctx.pending_rpcs_mut().complete(reply) {
+                // e.g. the caller stopped waiting, or the reply is a duplicate
+                eprintln!("Dropping reply nobody is waiting for: {reply:?}");
+            }
+        });
     }
 }
 
 impl<S> RouterService<S> {
-    pub fn route_message_to_handler(&self, req: &str) -> Result<String, MaelstromError> {
-        let req = Message::<Value>::from_str(req)?;
-
-        let req_type = req
-            .body
-            .content
-            .get("type")
-            .ok_or(UnknownRequestType)?
-            .clone();
-
-        let req_type_enum = from_value::<RequestTypes>(req_type)?;
-
-        let handler_fn = self
-            .handlers
-            .get(&req_type_enum)
-            .ok_or(NoHandlerForRequestType)?;
-
-        self.context
-            .clone()
-            .write()
-            .map(|mut ctx| {
-                if req_type_enum == RequestTypes::Topology {
-                    dbg!(&req_type_enum, &req);
-                } else {
-                    dbg!(&req_type_enum);
-                }
-                ctx.message_type(req_type_enum);
-            })
-            .map_err(|e| MaelstromError::PoisonError(e.to_string()))?;
+    /// Returns the handler's response, or `None` if the request was buffered until the node
+    /// is initialized.
+    pub fn route_message_to_handler(
+        &self,
+        req: RawRequest,
+    ) -> impl Future<Output = Result<Option<HandlerFuture>, MaelstromError>> + Send + 'static {
+        let context = self.context.clone();
+        let route = req
+            .msg_type()
+            .ok_or(UnknownRequestType)
+            .and_then(|req_type| {
+                let route = match req.workload() {
+                    Some(workload) => Cow::Owned(req_type.in_workload(workload)),
+                    None => Cow::Borrowed(req_type),
+                };
+                self.handlers
+                    .get(route.as_ref())
+                    .map(|handler| (req_type.clone(), handler.clone()))
+                    .ok_or_else(|| NotSupported(format!("{route} is not supported")))
+            });
 
-        handler_fn(self.context.clone(), req.to_value()?)
+        async move {
+            let (req_type, handler) = route?;
+            let is_init = req_type == RequestType::INIT;
+            let admitted = context
+                .call(move |ctx| {
+                    match ctx.state() {
+                        NodeState::Initialized if is_init => return Err(NodeAlreadyInitialized),
+                        NodeState::Uninitialized if !is_init && ctx.buffers_until_init() => {
+                            ctx.buffer_request(req)?;
+                            return Ok(None);
+                        }
+                        NodeState::Uninitialized if !is_init => return Err(NodeNotInitialized),
+                        _ => {}
+                    }
+                    Ok(Some(req))
+                })
+                .await??;
+
+            Ok(admitted.map(|req| handler.response(context, req)))
+        }
     }
 }
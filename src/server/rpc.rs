@@ -0,0 +1,415 @@
+use crate::{
+    error::{
+        MaelstromError::{self, NodeStopped, OutboundClosed, SerdeJsonError, Timeout},
+        MaelstromErrorBody,
+    },
+    message::{Body, Message, MsgId},
+    server::stdio::SharedIoServerContext,
+};
+use futures::future::{ready, BoxFuture, FutureExt};
+use rand::Rng;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    sync::oneshot,
+    time::{self, Sleep},
+};
+use tower::{retry::Policy, BoxError, Service, ServiceBuilder, ServiceExt};
+
+pub type Reply = Message<Value>;
+
+/// The RPCs sent by the node that are still waiting for their reply, keyed by `msg_id`.
+#[derive(Debug, Default)]
+pub struct PendingRpcs {
+    waiting: HashMap<MsgId, oneshot::Sender<Reply>>,
+}
+
+impl PendingRpcs {
+    fn register(&mut self, msg_id: MsgId, sender: oneshot::Sender<Reply>) {
+        self.waiting.insert(msg_id, sender);
+    }
+
+    /// Forgets the RPC whose caller stopped waiting, e.g. after a timeout.
+    fn forget(&mut self, msg_id: MsgId) {
+        self.waiting.remove(&msg_id);
+    }
+
+    /// Hands `reply` to the RPC it's in reply to. The reply is given back if no RPC is waiting
+    /// for it, e.g. when it arrived after the caller gave up.
+    pub fn complete(&mut self, reply: Reply) -> Result<(), Reply> {
+        let sender = reply
+            .body
+            .in_reply_to
+            .and_then(|msg_id| self.waiting.remove(&msg_id));
+        match sender {
+            Some(sender) => sender.send(reply),
+            None => Err(reply),
+        }
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.waiting.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Sends requests to other nodes (or Maelstrom services) and waits for their replies.
+pub trait RpcClient {
+    /// Sends `body` to `dest` with a fresh `msg_id`, resolving to the reply for it.
+    ///
+    /// An `error` reply resolves to the [`MaelstromError`] for its code. The call times out
+    /// after the timeout of [`RpcPolicy::default`], without retries.
+    fn rpc<T: Serialize>(&self, dest: impl Into<String>, body: T) -> RpcCall;
+
+    /// Sends `body` to `dest` like [`RpcClient::rpc`], with the timeout and retries of `policy`.
+    ///
+    /// The first attempt is sent right away, before the returned future is polled.
+    fn rpc_with<T: Serialize>(
+        &self,
+        dest: impl Into<String>,
+        body: T,
+        policy: &RpcPolicy,
+    ) -> BoxFuture<'static, Result<Reply, MaelstromError>>;
+}
+
+impl RpcClient for SharedIoServerContext {
+    fn rpc<T: Serialize>(&self, dest: impl Into<String>, body: T) -> RpcCall {
+        match serde_json::to_value(body) {
+            Ok(body) => send_rpc(self, dest.into(), body).timeout(RpcPolicy::default().timeout),
+            Err(e) => RpcCall::failed(SerdeJsonError(e)),
+        }
+    }
+
+    fn rpc_with<T: Serialize>(
+        &self,
+        dest: impl Into<String>,
+        body: T,
+        policy: &RpcPolicy,
+    ) -> BoxFuture<'static, Result<Reply, MaelstromError>> {
+        let body = match serde_json::to_value(body) {
+            Ok(body) => body,
+            Err(e) => return ready(Err(SerdeJsonError(e))).boxed(),
+        };
+        let request = RpcRequest::new(dest, body);
+        let mut service = policy.service(self.clone());
+        match service.ready().now_or_never() {
+            Some(Ok(service)) => service.call(request).boxed(),
+            _ => service.oneshot(request).boxed(),
+        }
+    }
+}
+
+/// Sends `body` to `dest` as an RPC, waiting for its reply without a timeout.
+fn send_rpc(context: &SharedIoServerContext, dest: String, body: Value) -> RpcCall {
+    // The node's task picks the `msg_id`, sends the request and waits for its reply in one
+    // step. If the request can't be sent, the sender is dropped and the call fails.
+    let (sender, receiver) = oneshot::channel();
+    let (registered, msg_id) = oneshot::channel();
+    let sent = context.cast(move |ctx| {
+        let msg_id = ctx.next_msg_id();
+        let request = Message::new(
+            ctx.node().clone(),
+            dest,
+            Body::new(Some(msg_id), None, body),
+        );
+        let sent = serde_json::to_string(&request)
+            .map_err(SerdeJsonError)
+            .and_then(|request| ctx.outbound().send(request));
+        match sent {
+            Ok(()) => {
+                ctx.pending_rpcs_mut().register(msg_id, sender);
+                let _ = registered.send(msg_id);
+            }
+            Err(e) => eprintln!("Unable to send RPC: {e}"),
+        }
+    });
+    match sent {
+        Ok(()) => RpcCall {
+            state: RpcState::Waiting {
+                reply: receiver,
+                msg_id,
+                context: context.clone(),
+            },
+            deadline: None,
+        },
+        Err(e) => RpcCall::failed(e),
+    }
+}
+
+/// An RPC to send through a [`RpcService`].
+#[derive(Clone, Debug)]
+pub struct RpcRequest {
+    pub dest: String,
+    pub body: Value,
+}
+
+impl RpcRequest {
+    pub fn new(dest: impl Into<String>, body: Value) -> Self {
+        Self {
+            dest: dest.into(),
+            body,
+        }
+    }
+}
+
+/// Tower service sending each request as an RPC and resolving to its reply, so RPCs can be
+/// wrapped in tower middleware like timeouts and retries.
+#[derive(Clone, Debug)]
+pub struct RpcService {
+    context: SharedIoServerContext,
+}
+
+impl RpcService {
+    #[must_use]
+    pub fn new(context: SharedIoServerContext) -> Self {
+        Self { context }
+    }
+}
+
+impl Service<RpcRequest> for RpcService {
+    type Response = Reply;
+    type Error = MaelstromError;
+    type Future = RpcCall;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: RpcRequest) -> Self::Future {
+        // The policy wrapping this service times the call out
+        send_rpc(&self.context, req.dest, req.body)
+    }
+}
+
+/// Timeout and retries of an RPC.
+///
+/// Each attempt times out with a `timeout` error (code 0) after the per-call timeout. Failed
+/// attempts are retried, with an exponential backoff, only if their error is indefinite, since
+/// definite errors mean the request can't succeed as is. Nothing is retried once the node
+/// can't send requests anymore.
+#[derive(Clone, Debug)]
+pub struct RpcPolicy {
+    timeout: Duration,
+    max_attempts: usize,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    jitter: bool,
+    attempts: usize,
+}
+
+impl Default for RpcPolicy {
+    /// A single attempt timing out after 1 second.
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(1),
+            max_attempts: 1,
+            min_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+            jitter: true,
+            attempts: 0,
+        }
+    }
+}
+
+impl RpcPolicy {
+    #[must_use]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how many times the RPC is sent at most, including the first attempt.
+    #[must_use]
+    pub fn max_attempts(mut self, attempts: usize) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    /// Sets the delay before the first retry, doubled on each retry up to `max`.
+    #[must_use]
+    pub fn backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_backoff = min;
+        self.max_backoff = max.max(min);
+        self
+    }
+
+    /// Picks each backoff at random between zero and its full delay (the default), so nodes
+    /// retrying at the same time spread out their retries.
+    #[must_use]
+    pub fn jitter(mut self, enabled: bool) -> Self {
+        self.jitter = enabled;
+        self
+    }
+
+    /// Wraps an [`RpcService`] in this policy's timeout and retries.
+    pub fn service(
+        &self,
+        context: SharedIoServerContext,
+    ) -> impl Service<RpcRequest, Response = Reply, Error = MaelstromError, Future: Send> + Send
+    {
+        ServiceBuilder::new()
+            .retry(self.clone())
+            .map_err(into_maelstrom_error)
+            .timeout(self.timeout)
+            .service(RpcService::new(context))
+    }
+
+    fn backoff_delay(&self) -> Duration {
+        backoff_delay(
+            self.min_backoff,
+            self.max_backoff,
+            self.jitter,
+            self.attempts,
+        )
+    }
+}
+
+/// The delay before the `retry`th retry (from 1): `min` doubled on each retry up to `max`,
+/// picked at random below it with `jitter`.
+pub(crate) fn backoff_delay(min: Duration, max: Duration, jitter: bool, retry: usize) -> Duration {
+    let exponent = u32::try_from(retry.saturating_sub(1)).unwrap_or(u32::MAX);
+    let delay = min.saturating_mul(2_u32.saturating_pow(exponent)).min(max);
+    if jitter && !delay.is_zero() {
+        rand::thread_rng().gen_range(Duration::ZERO..=delay)
+    } else {
+        delay
+    }
+}
+
+impl<Res> Policy<RpcRequest, Res, MaelstromError> for RpcPolicy {
+    type Future = time::Sleep;
+
+    fn retry(
+        &mut self,
+        _req: &mut RpcRequest,
+        result: &mut Result<Res, MaelstromError>,
+    ) -> Option<Self::Future> {
+        match result {
+            // The node can't send the request anymore, so retrying can't help
+            Err(OutboundClosed | NodeStopped) => None,
+            Err(e) if !e.definite() => {
+                self.attempts += 1;
+                (self.attempts < self.max_attempts).then(|| time::sleep(self.backoff_delay()))
+            }
+            _ => None,
+        }
+    }
+
+    fn clone_request(&mut self, req: &RpcRequest) -> Option<RpcRequest> {
+        Some(req.clone())
+    }
+}
+
+fn into_maelstrom_error(error: BoxError) -> MaelstromError {
+    match error.downcast::<MaelstromError>() {
+        Ok(error) => *error,
+        // The only other error is the timeout's `Elapsed`
+        Err(error) => Timeout(error.to_string()),
+    }
+}
+
+/// Future resolving to the reply of an RPC. Dropping it stops waiting for the reply.
+#[derive(Debug)]
+pub struct RpcCall {
+    state: RpcState,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+#[derive(Debug)]
+enum RpcState {
+    Waiting {
+        reply: oneshot::Receiver<Reply>,
+        /// The `msg_id` of the request, once the node's task sent it.
+        msg_id: oneshot::Receiver<MsgId>,
+        context: SharedIoServerContext,
+    },
+    Failed(Option<MaelstromError>),
+    Done,
+}
+
+impl RpcCall {
+    fn failed(error: MaelstromError) -> Self {
+        Self {
+            state: RpcState::Failed(Some(error)),
+            deadline: None,
+        }
+    }
+
+    /// Fails the call with a `timeout` error if no reply arrived after `timeout`.
+    fn timeout(mut self, timeout: Duration) -> Self {
+        self.deadline = Some(Box::pin(time::sleep(timeout)));
+        self
+    }
+
+    /// Stops waiting for the reply, so the node forgets the RPC.
+    fn forget(&mut self) {
+        if let RpcState::Waiting {
+            mut msg_id,
+            context,
+            ..
+        } = std::mem::replace(&mut self.state, RpcState::Done)
+        {
+            // Commands run in order, so the request was sent (or failed) by then
+            let _ = context.cast(move |ctx| {
+                if let Ok(msg_id) = msg_id.try_recv() {
+                    ctx.pending_rpcs_mut().forget(msg_id);
+                }
+            });
+        }
+    }
+}
+
+impl Future for RpcCall {
+    type Output = Result<Reply, MaelstromError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let result = match &mut this.state {
+            RpcState::Waiting { reply, .. } => match reply.poll_unpin(cx) {
+                Poll::Ready(reply) => reply.map_err(|_| OutboundClosed).and_then(into_result),
+                Poll::Pending => {
+                    let timed_out = this
+                        .deadline
+                        .as_mut()
+                        .is_some_and(|deadline| deadline.as_mut().poll(cx).is_ready());
+                    if !timed_out {
+                        return Poll::Pending;
+                    }
+                    this.forget();
+                    return Poll::Ready(Err(Timeout("request timed out".into())));
+                }
+            },
+            RpcState::Failed(error) => Err(error.take().unwrap_or(OutboundClosed)),
+            RpcState::Done => Err(OutboundClosed),
+        };
+        // The reply removed the RPC from the node's pending RPCs
+        this.state = RpcState::Done;
+        Poll::Ready(result)
+    }
+}
+
+impl Drop for RpcCall {
+    fn drop(&mut self) {
+        self.forget();
+    }
+}
+
+fn into_result(reply: Reply) -> Result<Reply, MaelstromError> {
+    if reply.body.content.get("type").and_then(Value::as_str) == Some("error") {
+        let error = serde_json::from_value::<MaelstromErrorBody>(reply.body.content)?;
+        return Err(error.into());
+    }
+    Ok(reply)
+}
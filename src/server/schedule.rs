@@ -0,0 +1,100 @@
+use crate::{error::MaelstromError, server::stdio::SharedIoServerContext};
+use futures::future::{BoxFuture, FutureExt};
+use rand::Rng;
+use std::{
+    fmt::{Debug, Formatter},
+    future::Future,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::time;
+
+pub type TaskFuture = BoxFuture<'static, Result<(), MaelstromError>>;
+
+type TaskFn = dyn Fn(SharedIoServerContext) -> TaskFuture + Send + Sync;
+
+/// Background work a workload runs every `interval` while the server is serving, e.g. gossip.
+///
+/// Register it with [`IoServer::schedule`](crate::server::stdio::IoServer::schedule).
+#[derive(Clone)]
+pub struct PeriodicTask {
+    interval: Duration,
+    jitter: Duration,
+    run_on_shutdown: bool,
+    task: Arc<TaskFn>,
+}
+
+impl PeriodicTask {
+    pub fn new<F, Fut>(interval: Duration, task: F) -> Self
+    where
+        F: Fn(SharedIoServerContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), MaelstromError>> + Send + 'static,
+    {
+        Self {
+            interval,
+            jitter: Duration::ZERO,
+            run_on_shutdown: false,
+            task: Arc::new(move |context| task(context).boxed()),
+        }
+    }
+
+    /// Delays every run by a random duration of up to `jitter`, so nodes started at the same
+    /// time don't all run their tasks in lockstep.
+    #[must_use]
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Runs the task one last time when the server shuts down, e.g. to queue pending gossip
+    /// before the outbound messages are flushed.
+    #[must_use]
+    pub fn run_on_shutdown(mut self, enabled: bool) -> Self {
+        self.run_on_shutdown = enabled;
+        self
+    }
+
+    /// Runs the task every interval until `shutdown` completes. Errors are ignored, so a failed
+    /// run is retried on the next tick.
+    pub(crate) async fn run(
+        self,
+        context: SharedIoServerContext,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        let mut interval = time::interval_at(time::Instant::now() + self.interval, self.interval);
+        tokio::pin!(shutdown);
+        loop {
+            let delay = self.delay();
+            tokio::select! {
+                () = async {
+                    interval.tick().await;
+                    time::sleep(delay).await;
+                } => {}
+                () = &mut shutdown => break,
+            }
+            let _ = (self.task)(context.clone()).await;
+        }
+
+        if self.run_on_shutdown {
+            let _ = (self.task)(context).await;
+        }
+    }
+
+    fn delay(&self) -> Duration {
+        if self.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            rand::thread_rng().gen_range(Duration::ZERO..=self.jitter)
+        }
+    }
+}
+
+impl Debug for PeriodicTask {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeriodicTask")
+            .field("interval", &self.interval)
+            .field("jitter", &self.jitter)
+            .field("run_on_shutdown", &self.run_on_shutdown)
+            .finish_non_exhaustive()
+    }
+}
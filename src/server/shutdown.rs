@@ -0,0 +1,60 @@
+use tokio::signal::ctrl_c;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+
+/// Handle used to ask a running [`IoServer`](crate::server::stdio::IoServer) to shut down.
+///
+/// Shutting down stops reading new requests, waits for the requests in flight, stops the
+/// background tasks and flushes any queued messages before `serve()` returns.
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownHandle {
+    token: CancellationToken,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.token.cancel();
+    }
+
+    #[must_use]
+    pub fn is_shutdown(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    pub fn wait(&self) -> WaitForCancellationFuture<'_> {
+        self.token.cancelled()
+    }
+
+    /// A token cancelled when the server shuts down, which can also be cancelled on its own.
+    pub(crate) fn child_token(&self) -> CancellationToken {
+        self.token.child_token()
+    }
+}
+
+/// Shuts the server down when the process receives SIGINT or SIGTERM, until it's shut down or
+/// the future is dropped.
+pub async fn shutdown_on_signal(handle: ShutdownHandle) {
+    tokio::select! {
+        result = signal() => {
+            if result.is_ok() {
+                handle.shutdown();
+            }
+        }
+        () = handle.wait() => {}
+    }
+}
+
+#[cfg(unix)]
+async fn signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() -> std::io::Result<()> {
+    ctrl_c().await
+}
@@ -0,0 +1,54 @@
+use std::{
+    any::{type_name, Any, TypeId},
+    collections::HashMap,
+    fmt::{Debug, Formatter},
+};
+
+/// State owned by the workloads a node runs, with one slot per type.
+///
+/// Each workload keeps its state in its own type (e.g. `broadcast::BroadcastState`), so adding
+/// a workload doesn't require adding fields to the
+/// [`IoServerContext`](crate::server::stdio::IoServerContext).
+#[derive(Default)]
+pub struct WorkloadStates {
+    slots: HashMap<TypeId, (&'static str, Box<dyn Any + Send + Sync>)>,
+}
+
+impl WorkloadStates {
+    #[must_use]
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.slots
+            .get(&TypeId::of::<T>())
+            .and_then(|(_, slot)| slot.downcast_ref())
+    }
+
+    /// Returns the state of type `T`, creating it with its default value if there's none yet.
+    ///
+    /// # Panics
+    ///
+    /// Never: slots are keyed by the `TypeId` of the state they hold.
+    pub fn get_mut<T: Any + Send + Sync + Default>(&mut self) -> &mut T {
+        self.slots
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| (type_name::<T>(), Box::new(T::default())))
+            .1
+            .downcast_mut()
+            .expect("state slots are keyed by their type")
+    }
+
+    /// Replaces the state of type `T`, returning the previous one.
+    pub fn insert<T: Any + Send + Sync>(&mut self, state: T) -> Option<T> {
+        self.slots
+            .insert(TypeId::of::<T>(), (type_name::<T>(), Box::new(state)))
+            .and_then(|(_, slot)| slot.downcast().ok())
+            .map(|slot| *slot)
+    }
+}
+
+impl Debug for WorkloadStates {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_set()
+            .entries(self.slots.values().map(|(name, _)| name))
+            .finish()
+    }
+}
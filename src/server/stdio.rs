@@ -1,29 +1,44 @@
 use crate::{
-    error::MaelstromError::{self, EndOfInput, PoisonError, RWLockError, SerdeJsonError},
+    error::MaelstromError::{
+        self, EndOfInput, NodeAlreadyInitialized, SerdeJsonError, StdinReadError,
+        TemporarilyUnavailable,
+    },
     message::{
-        broadcast::{Handler as BroadcastHandler, Request, RequestBody, SyncBody},
-        echo::Handler as EchoHandler,
-        g_counter,
-        g_counter::Handler as GcounterHandler,
-        generate::Handler as GenerateHandler,
-        init::Handler as InitHandler,
-        send_request, Body, Message, MsgId, RequestTypes, WorkloadHandler,
+        broadcast, broadcast::Handler as BroadcastHandler, echo::Handler as EchoHandler, g_counter,
+        g_counter::Handler as GcounterHandler, generate::Handler as GenerateHandler,
+        init::Handler as InitHandler, Body, Message, MsgId, RawRequest, RequestType,
+        WorkloadHandler,
+    },
+    server::{
+        node::{Node, NodeHandle},
+        outbound::{Outbound, OutboundWriter, OutputBatching},
+        panic::CatchPanicLayer,
+        router::{ErrorReply, HandlerMap, RouterLayer, SharedHandler},
+        rpc::PendingRpcs,
+        schedule::PeriodicTask,
+        shutdown::{shutdown_on_signal, ShutdownHandle},
+        state::WorkloadStates,
+        timer::{TimerHandle, TimerReceiver, Timers},
+        transport::Transport,
     },
-    server::router::{HandlerFn, HandlerMap, RouterLayer},
 };
 use futures::future::{ready, Ready};
+use serde::Serialize;
 use std::{
-    collections::{HashMap, HashSet},
+    any::Any,
+    collections::HashMap,
     fmt::Debug,
-    io::{stdout, BufRead, BufWriter, Write},
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc, RwLock,
-    },
+    io::{ErrorKind, Write},
+    sync::Arc,
     task::{Context, Poll},
-    time::{Duration, Instant},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, Lines},
+    sync::{Mutex, Semaphore},
+    task::JoinSet,
+    time,
 };
-use tokio::{sync::Mutex, time};
 use tower::{Service, ServiceBuilder};
 
 #[derive(Clone, Debug)]
@@ -54,139 +69,90 @@ impl<O: Write> Service<String> for StdOutService<O> {
             return ready(Ok(output_string));
         }
 
-        let output = &mut self.inner;
-        let result = output.write(output_string.as_bytes());
-
-        if let Err(e) = result {
-            return ready(Err(e.into()));
-        }
-        let _ = output.write(b"\n");
+        // Write the message and its newline at once, so unbuffered outputs get a single write
+        let mut line = Vec::with_capacity(output_string.len() + 1);
+        line.extend_from_slice(output_string.as_bytes());
+        line.push(b'\n');
 
-        if let Err(e) = output.flush() {
-            ready(Err(e.into()))
-        } else {
-            ready(Ok(output_string))
-        }
+        let output = &mut self.inner;
+        let result = output.write_all(&line).and_then(|()| output.flush());
+        ready(result.map(|()| output_string).map_err(Into::into))
     }
 }
 
-pub type SharedIoServerContext = Arc<RwLock<IoServerContext>>;
+/// Handle through which handlers and tasks reach the state of their node.
+pub type SharedIoServerContext = NodeHandle;
 
 pub type NumericMessage = usize;
-pub type MessageList = HashSet<NumericMessage>;
-pub type NodeCounters = HashMap<String, usize>;
-pub type NodeMessages = HashMap<String, MessageList>;
 
-#[derive(Debug, Clone)]
+/// Lifecycle of a node: it only handles workload requests once it got its `init` message.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum NodeState {
+    #[default]
+    Uninitialized,
+    Initialized,
+}
+
+/// How many requests a node holds until `init`, see [`IoServer::buffer_until_init`].
+pub const MAX_BUFFERED_REQUESTS: usize = 1024;
+
+/// Core state of a node, shared by every workload it runs.
+///
+/// Workload-specific state lives in typed slots, see [`IoServerContext::workload_state`].
+#[derive(Debug, Default)]
 pub struct IoServerContext {
+    state: NodeState,
+    buffer_until_init: bool,
+    pending_requests: Vec<RawRequest>,
     node_id: String,
     neighbors: Vec<String>,
-    messages_queued: NodeMessages,
-    messages_saved: MessageList,
-    counter: Arc<AtomicUsize>,
-    node_counters: NodeCounters,
-    messages_neighbors_have: NodeMessages,
-    message_type: RequestTypes,
-    last_sync: Instant,
     msg_id: MsgId,
+    outbound: Outbound,
+    timers: Timers,
+    rpcs: PendingRpcs,
+    workloads: WorkloadStates,
 }
 
-impl Default for IoServerContext {
-    fn default() -> Self {
-        Self {
-            node_id: String::new(),
-            neighbors: Vec::default(),
-            messages_queued: HashMap::default(),
-            messages_saved: HashSet::default(),
-            counter: Arc::new(AtomicUsize::new(0)),
-            node_counters: HashMap::default(),
-            messages_neighbors_have: HashMap::default(),
-            message_type: RequestTypes::default(),
-            last_sync: Instant::now(),
-            msg_id: 0,
-        }
-    }
-}
-
-pub trait BroadcastContext {
-    fn add_message(&mut self, source: String, message: NumericMessage);
-    #[must_use]
-    fn messages(self) -> Vec<NumericMessage>;
-}
-
-impl BroadcastContext for IoServerContext {
-    fn add_message(&mut self, source: String, message: NumericMessage) {
-        self.messages_saved.insert(message);
-        self.synced(source, HashSet::from([message]));
-    }
-
-    #[must_use]
-    fn messages(self) -> Vec<NumericMessage> {
-        let mut list: Vec<NumericMessage> = self.messages_saved.into_iter().collect();
-        list.sort_unstable();
-        list
-    }
-}
-pub trait GCounterContext {
-    fn add_node_counter(&mut self, node: String, counter: NumericMessage);
-    fn cas_local_node_counter(&mut self, value: NumericMessage) -> usize;
-    fn counter(&self) -> NumericMessage;
-    fn max_node_counter(&mut self) -> usize;
-    fn node_counters(&self) -> &NodeCounters;
-    fn set_counter(&mut self, value: NumericMessage);
-    fn update_all_node_counters(&mut self, new_counters: NodeCounters);
-    fn update_local_node_counter(&mut self, delta: NumericMessage);
-}
-
-impl GCounterContext for IoServerContext {
-    fn add_node_counter(&mut self, node: String, counter: NumericMessage) {
-        self.node_counters
-            .entry(node.clone())
-            .and_modify(|v| *v += counter)
-            .or_insert(counter);
-
-        let max_counter = self.max_node_counter();
-        self.cas_local_node_counter(counter.max(max_counter));
-    }
-
-    fn cas_local_node_counter(&mut self, value: NumericMessage) -> usize {
-        self.counter.fetch_max(value, Ordering::SeqCst);
-        self.counter()
-    }
-
+impl IoServerContext {
     #[must_use]
-    fn counter(&self) -> NumericMessage {
-        self.counter.load(Ordering::SeqCst)
+    pub fn state(&self) -> NodeState {
+        self.state
     }
 
-    fn max_node_counter(&mut self) -> usize {
-        self.node_counters.values().sum::<usize>()
+    /// Sets the node's ID and neighbors, moving it to the [`NodeState::Initialized`] state.
+    pub fn initialize(&mut self, node: String, node_ids: &[String]) -> Result<(), MaelstromError> {
+        if self.state == NodeState::Initialized {
+            return Err(NodeAlreadyInitialized);
+        }
+        self.set_node(node);
+        self.set_neighbors(node_ids);
+        self.outbound.set_nodes(node_ids);
+        self.state = NodeState::Initialized;
+        Ok(())
     }
 
     #[must_use]
-    fn node_counters(&self) -> &NodeCounters {
-        &self.node_counters
-    }
-
-    fn set_counter(&mut self, value: NumericMessage) {
-        self.counter.store(value, Ordering::SeqCst);
+    pub fn buffers_until_init(&self) -> bool {
+        self.buffer_until_init
     }
 
-    fn update_all_node_counters(&mut self, new_counters: NodeCounters) {
-        for (key, value) in new_counters {
-            // Replace all node counter except self
-            if &key != self.node() {
-                *self.node_counters.entry(key.to_string()).or_default() = value;
-            }
+    /// Holds a request that arrived before `init`, so it can be handled once the node is
+    /// initialized. Fails with [`TemporarilyUnavailable`] once
+    /// [`MAX_BUFFERED_REQUESTS`] are held.
+    pub fn buffer_request(&mut self, req: RawRequest) -> Result<(), MaelstromError> {
+        if self.pending_requests.len() >= MAX_BUFFERED_REQUESTS {
+            return Err(TemporarilyUnavailable(
+                "too many requests waiting for init".into(),
+            ));
         }
+        self.pending_requests.push(req);
+        Ok(())
     }
 
-    fn update_local_node_counter(&mut self, delta: NumericMessage) {
-        let _max = self.counter.fetch_add(delta, Ordering::SeqCst);
+    pub fn take_buffered_requests(&mut self) -> Vec<RawRequest> {
+        std::mem::take(&mut self.pending_requests)
     }
-}
 
-impl IoServerContext {
     #[must_use]
     pub fn node(&self) -> &String {
         &self.node_id
@@ -208,177 +174,440 @@ impl IoServerContext {
             .collect();
     }
 
-    pub fn message_type(&mut self, message_type: RequestTypes) {
-        self.message_type = message_type;
+    pub fn next_msg_id(&mut self) -> MsgId {
+        self.msg_id += 1;
+        self.msg_id
     }
 
-    pub fn synced(&mut self, node: String, messages: MessageList) -> &NodeMessages {
-        self.messages_saved.extend(messages.clone());
-        if self.neighbors.contains(&node) {
-            self.messages_neighbors_have
-                .entry(node)
-                .or_default()
-                .extend(messages);
-        }
-        let mut list = NodeMessages::new();
-        for node in &self.neighbors {
-            let messages_node_has = self
-                .messages_neighbors_have
-                .entry(node.clone())
-                .or_default()
-                .iter()
-                .copied()
-                .collect::<HashSet<_>>();
-            let mut missing_messages = self
-                .messages_saved
-                .difference(&messages_node_has)
-                .copied()
-                .collect::<HashSet<_>>();
-
-            let extra_known_messages_limit = (missing_messages.len() * 10 / 100).max(10);
-            let extra_known_messages = messages_node_has
-                .iter()
-                .enumerate()
-                .filter(|(count, _)| count < &extra_known_messages_limit)
-                .map(|(_, message)| message)
-                .copied()
-                .collect::<HashSet<_>>();
-            missing_messages.extend(extra_known_messages);
-            list.insert(node.clone(), missing_messages);
-        }
-        for (node, messages) in list {
-            self.queue_message_to_send(node, &messages);
-        }
-        &self.messages_queued
+    #[must_use]
+    pub fn outbound(&self) -> &Outbound {
+        &self.outbound
     }
 
-    pub fn queue_message_to_send(&mut self, node: String, message: &MessageList) {
-        if !message.is_empty() {
-            self.messages_queued
-                .entry(node)
-                .or_default()
-                .extend(message);
-        }
+    #[must_use]
+    pub fn pending_rpcs(&self) -> &PendingRpcs {
+        &self.rpcs
     }
 
-    pub fn next_msg_id(&mut self) -> MsgId {
-        self.msg_id += 1;
-        self.msg_id
+    pub fn pending_rpcs_mut(&mut self) -> &mut PendingRpcs {
+        &mut self.rpcs
+    }
+
+    /// Delivers `event` to the node's own handlers once, after `delay`.
+    ///
+    /// The event is the body of an internal message from the node to itself, so it's routed by
+    /// its `type` like any request. Errors from its handler are logged instead of replied to.
+    pub fn schedule_after<T: Serialize>(
+        &self,
+        delay: Duration,
+        event: T,
+    ) -> Result<TimerHandle, MaelstromError> {
+        Ok(self.timers.after(delay, self.internal_message(event)?))
+    }
+
+    /// Delivers `event` to the node's own handlers every `period`, until the returned handle is
+    /// cancelled. See [`IoServerContext::schedule_after`].
+    pub fn every<T: Serialize>(
+        &self,
+        period: Duration,
+        event: T,
+    ) -> Result<TimerHandle, MaelstromError> {
+        Ok(self.timers.every(period, self.internal_message(event)?))
+    }
+
+    fn internal_message<T: Serialize>(&self, event: T) -> Result<String, MaelstromError> {
+        let node = self.node().clone();
+        let message = Message::new(node.clone(), node, Body::new(None, None, event));
+        serde_json::to_string(&message).map_err(SerdeJsonError)
+    }
+
+    /// Returns the workload state of type `T`, if the workload created it.
+    #[must_use]
+    pub fn workload_state<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.workloads.get()
+    }
+
+    /// Returns the workload state of type `T`, creating it with its default value if needed.
+    pub fn workload_state_mut<T: Any + Send + Sync + Default>(&mut self) -> &mut T {
+        self.workloads.get_mut()
+    }
+
+    /// Sets the workload state of type `T`, returning the previous one.
+    pub fn set_workload_state<T: Any + Send + Sync>(&mut self, state: T) -> Option<T> {
+        self.workloads.insert(state)
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct IoServer<I, O>
 where
-    I: BufRead,
+    I: AsyncBufRead,
     O: Write,
 {
-    input: Arc<RwLock<I>>,
+    input: Arc<Mutex<Lines<I>>>,
     output: Arc<Mutex<O>>,
+    outbound: Arc<Mutex<OutboundWriter>>,
+    timers: Arc<Mutex<TimerReceiver>>,
     handlers: HandlerMap,
+    node: Arc<Mutex<Option<Node>>>,
     context: SharedIoServerContext,
+    limits: RequestLimits,
+    output_batching: OutputBatching,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    tasks: Vec<PeriodicTask>,
+}
+
+/// How many requests a server processes at the same time, and how many more it queues.
+#[derive(Clone, Copy, Debug)]
+struct RequestLimits {
+    concurrency: usize,
+    queued: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            concurrency: 16,
+            queued: 1024,
+        }
+    }
 }
 
 impl<I, O> IoServer<I, O>
 where
-    I: BufRead,
+    I: AsyncBufRead + Unpin,
     O: Write,
 {
+    /// Creates a server that reads one request per line from `input`.
+    ///
+    /// Blocking readers can be used by wrapping them in a
+    /// [`SyncBufReader`](crate::server::input::SyncBufReader).
     pub fn new(input: I, output: O) -> Self {
-        let input = Arc::new(RwLock::new(input));
+        let input = Arc::new(Mutex::new(input.lines()));
         let output = Arc::new(Mutex::new(output));
+        let (outbound, receiver) = Outbound::channel();
+        let shutdown = ShutdownHandle::default();
+        let (timers, timer_receiver) = Timers::channel(shutdown.clone());
+        let context = IoServerContext {
+            outbound,
+            timers,
+            ..IoServerContext::default()
+        };
+        let (node, context) = Node::new(context);
         let mut server = Self {
             input,
             output,
+            outbound: Arc::new(Mutex::new(OutboundWriter::new(receiver))),
+            timers: Arc::new(Mutex::new(timer_receiver)),
             handlers: HashMap::default(),
-            context: Arc::default(),
+            node: Arc::new(Mutex::new(Some(node))),
+            context,
+            limits: RequestLimits::default(),
+            output_batching: OutputBatching::default(),
+            shutdown,
+            shutdown_timeout: Duration::from_secs(1),
+            tasks: Vec::new(),
         };
-        server.register(RequestTypes::Init, InitHandler::response);
+        server.register(RequestType::INIT, InitHandler);
         server
     }
 
+    /// Creates a server for the input and output opened by `transport`.
+    pub fn with_transport<T>(transport: T) -> Result<Self, MaelstromError>
+    where
+        T: Transport<Input = I, Output = O>,
+    {
+        let (input, output) = transport.open()?;
+        Ok(Self::new(input, output))
+    }
+
+    /// Serves requests until the input ends or the server is shut down.
+    ///
+    /// Before returning, the requests still in flight are given up to the shutdown timeout
+    /// to complete, background tasks are stopped and all queued messages are written out.
     pub async fn serve(&mut self) -> Result<(), MaelstromError> {
+        // The node's task runs until the server and every handle it gave out are dropped
+        if let Some(node) = self.node.lock().await.take() {
+            tokio::spawn(node.run());
+        }
+        self.outbound
+            .lock()
+            .await
+            .set_batching(self.output_batching);
+        let shutdown = self.shutdown.clone();
+        let mut tasks = JoinSet::new();
+        for task in self.tasks.clone() {
+            let shutdown = shutdown.clone();
+            tasks.spawn(task.run(self.context.clone(), async move { shutdown.wait().await }));
+        }
+
+        let handlers = Arc::new(self.handlers.clone());
+        let limits = self.limits;
+        let timeout = self.shutdown_timeout;
+        let input = self.input.clone();
+        let timers = self.timers.clone();
         let context = self.context.clone();
-        tokio::spawn(async move {
-            let mut retry_interval = time::interval(Duration::from_millis(125));
-            let mut last_tick = Instant::now();
-            loop {
-                retry_interval.tick().await;
-                if last_tick.elapsed() > Duration::from_secs(1) {
-                    last_tick = Instant::now();
-                    let _ = deliver_counters(&context).await;
-                }
-                let _ = retry_sync_messages(&context).await;
+        let serving = main_loop(
+            input,
+            timers,
+            context,
+            handlers,
+            limits,
+            shutdown.clone(),
+            timeout,
+        );
+        // Signals are only listened to while serving, so they're left alone once it returns
+        let serving = async {
+            tokio::pin!(serving);
+            tokio::select! {
+                result = &mut serving => result,
+                () = shutdown_on_signal(shutdown.clone()) => serving.await,
             }
-        });
+        };
+        let result = tokio::select! {
+            result = serving => result,
+            result = self.write_outbound() => result,
+        };
 
-        loop {
-            let context = self.context.clone();
-            let handlers = self.handlers.clone();
-            let input = self.input.clone();
-            let output = self.output.clone();
+        // Stop the periodic tasks and give them a chance to queue their last messages
+        shutdown.shutdown();
+        let stopped = async { while tasks.join_next().await.is_some() {} };
+        if time::timeout(timeout, stopped).await.is_err() {
+            tasks.abort_all();
+        }
 
-            if let Err(EndOfInput) = main_loop(input, output, context, handlers.into()).await {
-                break;
+        // The messages the tasks and handlers sent through the node's task are only queued once
+        // it applied every command sent before this one
+        let _ = time::timeout(timeout, self.context.call(|_| ())).await;
+
+        // Write the replies and gossip that were still queued when serving stopped
+        self.flush_outbound().await?;
+        result
+    }
+
+    /// Buffers the requests that arrive before `init` and handles them once the node is
+    /// initialized, instead of replying with a `NodeNotInitialized` error (the default).
+    ///
+    /// Up to [`MAX_BUFFERED_REQUESTS`] are buffered, the ones after are rejected with a
+    /// `temporarily-unavailable` error. Once `init` succeeds, the buffered requests are
+    /// processed like the ones read after it, within the same limits.
+    pub fn buffer_until_init(&mut self, enabled: bool) -> &mut Self {
+        // Applied before any request, since the node's task starts when serving
+        let _ = self
+            .context
+            .cast(move |ctx| ctx.buffer_until_init = enabled);
+        self
+    }
+
+    /// Returns a handle that can be used to shut the server down while it's serving.
+    ///
+    /// SIGINT and SIGTERM trigger the same shutdown.
+    #[must_use]
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Runs `task` in the background while the server is serving.
+    pub fn schedule(&mut self, task: PeriodicTask) -> &mut Self {
+        self.tasks.push(task);
+        self
+    }
+
+    /// Sets how long a shutdown waits for requests in flight and periodic tasks
+    /// (defaults to 1 second).
+    pub fn shutdown_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Sets how many requests may be processed at the same time (defaults to 16).
+    ///
+    /// Requests are dispatched to their own task, and their replies are written as they
+    /// complete, so with a limit above 1 replies can be written out of order.
+    pub fn concurrency_limit(&mut self, limit: usize) -> &mut Self {
+        self.limits.concurrency = limit.max(1);
+        self
+    }
+
+    /// Sets how many requests may wait for one of the requests being processed to complete
+    /// (defaults to 1024).
+    ///
+    /// The input is still read when the queue is full, so RPC replies keep being delivered,
+    /// but the requests that don't fit are rejected with a `temporarily-unavailable` error
+    /// (code 11), which clients can retry.
+    pub fn queue_limit(&mut self, limit: usize) -> &mut Self {
+        self.limits.queued = limit;
+        self
+    }
+
+    /// Sets how outbound messages are batched before being written (see [`OutputBatching`]).
+    ///
+    /// The setting is applied when the server starts serving.
+    pub fn output_batching(&mut self, batching: OutputBatching) -> &mut Self {
+        self.output_batching = batching;
+        self
+    }
+
+    /// Writes outbound messages to the server's output in batches as they are queued.
+    async fn write_outbound(&self) -> Result<(), MaelstromError> {
+        let mut outbound = self.outbound.lock().await;
+        while outbound.next_batch().await {
+            let mut output = self.output.lock().await;
+            outbound.write_to(&mut *output)?;
+        }
+        Ok(())
+    }
+
+    /// Writes every queued outbound message to the server's output.
+    async fn flush_outbound(&self) -> Result<(), MaelstromError> {
+        let mut outbound = self.outbound.lock().await;
+        let mut output = self.output.lock().await;
+        outbound.drain();
+        outbound.write_to(&mut *output)
+    }
+
+    /// Registers the handlers and periodic tasks of a built-in workload.
+    ///
+    /// Workloads are composed with the ones already registered, so a node can serve several.
+    /// A request type shared by several workloads, like `read`, is owned by the first one
+    /// registered, see [`IoServer::compose`].
+    pub fn workload(&mut self, io_type: IoServerType) -> &mut Self {
+        let workload = io_type.name();
+        match io_type {
+            IoServerType::Echo => self.compose(workload, RequestType::ECHO, EchoHandler),
+            IoServerType::Broadcast => {
+                let handler = Arc::new(BroadcastHandler);
+                self.compose(workload, RequestType::BROADCAST, handler.clone())
+                    .compose(workload, RequestType::READ, handler.clone())
+                    .compose(workload, RequestType::SYNC, handler.clone())
+                    .compose(workload, RequestType::TOPOLOGY, handler)
+                    .schedule(broadcast::gossip_task())
+            }
+            IoServerType::Gcounter => {
+                let handler = Arc::new(GcounterHandler);
+                self.compose(workload, RequestType::ADD, handler.clone())
+                    .compose(workload, RequestType::READ, handler.clone())
+                    .compose(workload, RequestType::SYNC_COUNTER, handler)
+                    .schedule(g_counter::counter_sync_task())
+            }
+            IoServerType::GcounterSeqKv => {
+                let handler = Arc::new(g_counter::SeqKvHandler);
+                self.compose(workload, RequestType::ADD, handler.clone())
+                    .compose(workload, RequestType::READ, handler)
             }
+            IoServerType::Generate => {
+                self.compose(workload, RequestType::GENERATE, GenerateHandler)
+            }
+            // `init` is always handled
+            IoServerType::Init => self,
         }
+    }
 
-        Ok(())
+    /// Routes requests of type `name` to `handler` unless another workload already owns that
+    /// type, e.g. `read` on a node serving both broadcast and g-counter.
+    ///
+    /// Requests naming the workload in their body, like
+    /// `{"type": "read", "workload": "g-counter"}`, are routed to its handler either way.
+    pub fn compose(
+        &mut self,
+        workload: &str,
+        name: impl Into<RequestType>,
+        handler: impl WorkloadHandler,
+    ) -> &mut Self {
+        let name = name.into();
+        let handler: SharedHandler = Arc::new(handler);
+        self.handlers
+            .insert(name.in_workload(workload), handler.clone());
+        self.handlers.entry(name).or_insert(handler);
+        self
     }
 
-    pub fn register(&mut self, name: RequestTypes, handler: HandlerFn) -> &mut Self {
-        HandlerMap::insert(&mut self.handlers, name, handler);
+    /// Routes requests with the given `type` to `handler`, replacing any handler registered for
+    /// it, e.g. `RequestType::ECHO` or any custom type name.
+    pub fn register(
+        &mut self,
+        name: impl Into<RequestType>,
+        handler: impl WorkloadHandler,
+    ) -> &mut Self {
+        HandlerMap::insert(&mut self.handlers, name.into(), Arc::new(handler));
         self
     }
 }
 
+/// The built-in workloads a node can serve.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum IoServerType {
     Echo,
     Broadcast,
     Gcounter,
+    /// G-counter stored in Maelstrom's `seq-kv` service, instead of gossiped between nodes.
+    GcounterSeqKv,
     Generate,
     Init,
 }
-pub async fn start_io_server<I: BufRead, O: Write>(
+
+impl IoServerType {
+    /// The name requests use to address the workload, see [`IoServer::compose`].
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Echo => "echo",
+            Self::Broadcast => "broadcast",
+            Self::Gcounter => "g-counter",
+            Self::GcounterSeqKv => "g-counter-seq-kv",
+            Self::Generate => "generate",
+            Self::Init => "init",
+        }
+    }
+}
+
+pub async fn start_io_server<I: AsyncBufRead + Unpin, O: Write>(
     input: I,
     output: O,
     io_type: IoServerType,
+) -> Result<(), MaelstromError> {
+    start_io_server_with(input, output, &[io_type]).await
+}
+
+/// Serves several workloads from the same node, e.g. broadcast and g-counter side by side.
+///
+/// Request types shared by the workloads, like `read`, are handled by the first one listed
+/// unless the request names another (see [`IoServer::compose`]).
+pub async fn start_io_server_with<I: AsyncBufRead + Unpin, O: Write>(
+    input: I,
+    output: O,
+    io_types: &[IoServerType],
 ) -> Result<(), MaelstromError> {
     let mut server = IoServer::new(input, output);
-    let init = server.register(RequestTypes::Init, InitHandler::response);
-
-    match io_type {
-        IoServerType::Echo => server.register(RequestTypes::Echo, EchoHandler::response),
-        IoServerType::Broadcast => server
-            .register(RequestTypes::Broadcast, BroadcastHandler::response)
-            .register(RequestTypes::Read, BroadcastHandler::response)
-            .register(RequestTypes::Sync, BroadcastHandler::response)
-            .register(RequestTypes::Topology, BroadcastHandler::response),
-        IoServerType::Gcounter => server
-            .register(RequestTypes::Add, GcounterHandler::response)
-            .register(RequestTypes::Read, GcounterHandler::response)
-            .register(RequestTypes::SyncCounter, GcounterHandler::response),
-        IoServerType::Generate => {
-            server.register(RequestTypes::Generate, GenerateHandler::response)
-        }
-        IoServerType::Init => init,
+    for io_type in io_types {
+        server.workload(*io_type);
     }
-    .serve()
-    .await
+    server.serve().await
+}
+
+/// Serves workloads over `transport`, e.g. as a node of a TCP cluster instead of under
+/// Maelstrom.
+pub async fn start_transport_server<T: Transport>(
+    transport: T,
+    io_types: &[IoServerType],
+) -> Result<(), MaelstromError> {
+    let (input, output) = transport.open()?;
+    start_io_server_with(input, output, io_types).await
 }
 
-async fn process_messages<O: Write>(
+async fn process_messages(
     context: SharedIoServerContext,
     handlers: Arc<HandlerMap>,
-    req: &str,
-    output: Arc<Mutex<O>>,
+    req: RawRequest,
 ) -> Result<String, MaelstromError> {
-    let mut output = output.lock().await;
+    let outbound = context.outbound().clone();
     let router = RouterLayer::new(context, handlers);
     ServiceBuilder::new()
+        .layer(CatchPanicLayer::new(outbound.clone()))
         .layer(router)
-        .service(StdOutService::new(&mut *output))
-        .call(req.to_string())
+        .service(outbound)
+        .call(req)
         .await
 }
 
@@ -386,126 +615,137 @@ pub async fn send_message<W: Write>(
     writer: W,
     output_message: String,
 ) -> Result<String, MaelstromError> {
-    let output = BufWriter::new(writer);
     ServiceBuilder::new()
-        .service(StdOutService::new(output))
+        .service(StdOutService::new(writer))
         .call(output_message)
         .await
 }
 
-async fn retry_sync_messages(context: &SharedIoServerContext) -> Result<usize, MaelstromError> {
-    let remaining = context
-        .read()
-        .map_err(|e| PoisonError(e.to_string()))
-        .map(|ctx| ctx.messages_queued.len())?;
-    if remaining == 0 {
-        return Ok(remaining);
-    }
-    sync_messages(context).await
-}
-
-async fn deliver_counters(context: &SharedIoServerContext) -> Result<(), MaelstromError> {
-    let (neighbors, counters) = context
-        .read()
-        .map(|ctx| (ctx.neighbors().clone(), ctx.node_counters.clone()))
-        .map_err(|e| PoisonError(e.to_string()))?;
-
-    // Don't try delivering counter if there are none
-    if counters.is_empty() {
-        return Ok(());
-    }
-
-    for node in neighbors {
-        let message = send_request(
-            node,
-            context,
-            g_counter::RequestBody::SyncCounter(counters.clone().into()),
-        );
+async fn main_loop<I: AsyncBufRead + Unpin>(
+    reader: Arc<Mutex<Lines<I>>>,
+    timers: Arc<Mutex<TimerReceiver>>,
+    context: SharedIoServerContext,
+    handlers: Arc<HandlerMap>,
+    limits: RequestLimits,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+) -> Result<(), MaelstromError> {
+    let mut queue = RequestQueue {
+        in_flight: JoinSet::new(),
+        permits: Arc::new(Semaphore::new(limits.concurrency)),
+        limits,
+        shutdown: shutdown.clone(),
+        context: context.clone(),
+        handlers: handlers.clone(),
+    };
+    let mut replayed_buffered = false;
+    let mut timers = timers.lock().await;
+    loop {
+        let request = tokio::select! {
+            () = shutdown.wait() => break,
+            request = read_request(&reader) => request,
+            Some(event) = timers.recv() => Ok(event),
+            Some(_) = queue.in_flight.join_next(), if !queue.in_flight.is_empty() => continue,
+        };
+        let input = match request {
+            Ok(input) => input,
+            Err(EndOfInput) => break,
+            // Skip lines that aren't valid UTF-8
+            Err(StdinReadError(e)) if e.kind() == ErrorKind::InvalidData => continue,
+            Err(e) => return Err(e),
+        };
+        let req = match RawRequest::parse(input) {
+            Ok(req) => req,
+            Err(e) => {
+                // There's no envelope to reply to, so the error can only be logged
+                eprintln!("Unable to parse request: {e}");
+                continue;
+            }
+        };
 
-        let sync_result = send_message(stdout(), message.serde_to_string()?).await;
-        if sync_result.is_err() {
-            // dbg!(&sync_result);
-            sync_result?;
+        // Replies only wake up the RPC waiting for them, so they are handled right away
+        // instead of waiting for a handler to finish. `init` is handled before reading on, so
+        // the requests after it find the node initialized.
+        if req.in_reply_to().is_some() {
+            let _ = process_messages(context.clone(), handlers.clone(), req).await;
+            continue;
         }
-    }
-    Ok(())
-}
-
-async fn sync_messages(context: &SharedIoServerContext) -> Result<usize, MaelstromError> {
-    let mut remaining: usize = 0;
-    let sync_result = context
-        .write()
-        .map(|mut ctx| {
-            let node = ctx.node_id.clone();
-            let mut pending_messages = Vec::new();
-            for (dest, messages) in ctx.messages_queued.drain() {
-                let mut messages_to_send = Vec::from_iter(messages);
-                messages_to_send.sort_unstable();
-                if !messages_to_send.is_empty() {
-                    let msg = (node.to_string(), dest.to_string(), messages_to_send);
-                    pending_messages.push(msg);
+        if req.msg_type() != Some(&RequestType::INIT) {
+            queue.dispatch(req);
+            continue;
+        }
+        let _ = process_messages(context.clone(), handlers.clone(), req).await;
+        if !replayed_buffered {
+            // The requests that arrived before `init` are queued like the ones read after it
+            let buffered = context
+                .call(|ctx| {
+                    (ctx.state() == NodeState::Initialized).then(|| ctx.take_buffered_requests())
+                })
+                .await;
+            if let Ok(Some(buffered)) = buffered {
+                replayed_buffered = true;
+                for req in buffered {
+                    queue.dispatch(req);
                 }
             }
-            remaining = ctx.messages_queued.len();
-            pending_messages
-        })
-        .map_err(|e| PoisonError(e.to_string()))?;
-
-    let pending_messages = context
-        .write()
-        .map(|mut ctx| {
-            let mut messages = Vec::new();
-            for (node, dest, messages_to_send) in sync_result {
-                let message = Request::new(Message::new(
-                    node.to_string(),
-                    dest.to_string(),
-                    Body::new(
-                        Some(ctx.next_msg_id()),
-                        None,
-                        RequestBody::Sync(SyncBody::new(messages_to_send)),
-                    ),
-                ));
-                messages.push(message);
-            }
-            ctx.last_sync = Instant::now();
-            messages
-        })
-        .map_err(|e| PoisonError(e.to_string()))?;
-
-    for message in pending_messages {
-        let sync_message = serde_json::to_string(&message).map_err(SerdeJsonError)?;
-        let sync_result = send_message(stdout(), sync_message).await;
-        if sync_result.is_err() {
-            // dbg!(&sync_result);
-            sync_result?;
         }
     }
 
-    Ok(remaining)
+    // Wait for the requests that are still in flight
+    let completed = async { while queue.in_flight.join_next().await.is_some() {} };
+    let _ = time::timeout(shutdown_timeout, completed).await;
+    Ok(())
 }
 
-async fn main_loop<I: BufRead, O: Write>(
-    reader: Arc<RwLock<I>>,
-    writer: Arc<Mutex<O>>,
+/// The requests being processed by [`main_loop`], or waiting for a permit to be.
+struct RequestQueue {
+    in_flight: JoinSet<()>,
+    permits: Arc<Semaphore>,
+    limits: RequestLimits,
+    shutdown: ShutdownHandle,
     context: SharedIoServerContext,
     handlers: Arc<HandlerMap>,
-) -> Result<(), MaelstromError> {
-    let mut input = String::new();
-    let input_result = reader
-        .write()
-        .map_err(|e| RWLockError(e.to_string()))?
-        .read_line(&mut input);
-    if let Ok(_size) = input_result {
-        if input.is_empty() {
-            return Err(EndOfInput);
+}
+
+impl RequestQueue {
+    /// Processes `req` in its own task, or rejects it if the queue is full.
+    fn dispatch(&mut self, req: RawRequest) {
+        if self.in_flight.len() >= self.limits.concurrency + self.limits.queued {
+            reject_overloaded(&self.context, &req);
+            return;
         }
 
-        let process_result = process_messages(context, handlers, &input, writer).await;
-        match process_result {
-            Ok(_response) => {}
-            Err(e) => return Err(e),
-        };
-        input.clear();
+        // The request waits for a permit in its own task, so the input keeps being read while
+        // every permit is taken, e.g. for the replies the handlers holding them are waiting for
+        let permits = self.permits.clone();
+        let shutdown = self.shutdown.clone();
+        let context = self.context.clone();
+        let handlers = self.handlers.clone();
+        self.in_flight.spawn(async move {
+            let permit = tokio::select! {
+                () = shutdown.wait() => return,
+                permit = permits.acquire_owned() => permit,
+            };
+            if let Ok(_permit) = permit {
+                let _ = process_messages(context, handlers, req).await;
+            }
+        });
     }
-    Ok(())
+}
+
+/// Replies to a request that doesn't fit in the queue, without processing it.
+fn reject_overloaded(context: &SharedIoServerContext, req: &RawRequest) {
+    let error = TemporarilyUnavailable("too many requests in flight".into());
+    let sent = ErrorReply::new(req, context.outbound())
+        .build(&error)
+        .and_then(|reply| context.outbound().send_message(reply));
+    if let Err(e) = sent {
+        eprintln!("Unable to reject request: {e}");
+    }
+}
+
+async fn read_request<I: AsyncBufRead + Unpin>(
+    reader: &Mutex<Lines<I>>,
+) -> Result<String, MaelstromError> {
+    reader.lock().await.next_line().await?.ok_or(EndOfInput)
 }
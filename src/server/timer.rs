@@ -0,0 +1,98 @@
+use crate::server::shutdown::ShutdownHandle;
+use std::time::Duration;
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time,
+};
+use tokio_util::sync::CancellationToken;
+
+pub type TimerReceiver = UnboundedReceiver<String>;
+
+/// Delivers messages back into the node's handler pipeline after a delay.
+///
+/// Timers run on the tokio clock, so tests with paused time can drive them, and are cancelled
+/// when their server shuts down.
+#[derive(Clone, Debug)]
+pub struct Timers {
+    sender: UnboundedSender<String>,
+    shutdown: ShutdownHandle,
+}
+
+impl Timers {
+    /// Creates the timers of a server, which are cancelled once `shutdown` is.
+    #[must_use]
+    pub fn channel(shutdown: ShutdownHandle) -> (Self, TimerReceiver) {
+        let (sender, receiver) = unbounded_channel();
+        (Self { sender, shutdown }, receiver)
+    }
+
+    fn handle(&self) -> TimerHandle {
+        TimerHandle {
+            token: self.shutdown.child_token(),
+        }
+    }
+
+    /// Delivers `message` once, after `delay`.
+    #[must_use]
+    pub fn after(&self, delay: Duration, message: String) -> TimerHandle {
+        let handle = self.handle();
+        let token = handle.token.clone();
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                () = time::sleep(delay) => {
+                    let _ = sender.send(message);
+                }
+                () = token.cancelled() => {}
+            }
+        });
+        handle
+    }
+
+    /// Delivers `message` every `period`, starting one period from now, until the timer is
+    /// cancelled or the server stops.
+    #[must_use]
+    pub fn every(&self, period: Duration, message: String) -> TimerHandle {
+        let handle = self.handle();
+        let token = handle.token.clone();
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval_at(time::Instant::now() + period, period);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        if sender.send(message.clone()).is_err() {
+                            break;
+                        }
+                    }
+                    () = token.cancelled() => break,
+                }
+            }
+        });
+        handle
+    }
+}
+
+impl Default for Timers {
+    /// Timers that aren't attached to a server. Their messages are dropped.
+    fn default() -> Self {
+        Self::channel(ShutdownHandle::default()).0
+    }
+}
+
+/// Cancels a timer. Dropping the handle leaves the timer running until its server shuts down.
+#[derive(Clone, Debug, Default)]
+pub struct TimerHandle {
+    token: CancellationToken,
+}
+
+impl TimerHandle {
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+}
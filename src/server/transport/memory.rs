@@ -0,0 +1,60 @@
+use crate::{
+    error::MaelstromError::NodeNotFound,
+    server::transport::{Routes, Transport, TransportInput, TransportOutput},
+};
+use std::io;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+/// An in-process network delivering messages between the nodes and clients that joined it.
+///
+/// Each member is reached by its id, so a test can run a whole cluster of
+/// [`IoServer`](crate::server::stdio::IoServer)s, and talk to it as a client, in one process.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryNetwork {
+    routes: Routes,
+}
+
+impl MemoryNetwork {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Joins the network as `id`. Messages sent to `id` are queued for the returned
+    /// transport's input from now on, even before it's opened.
+    pub fn join(&self, id: impl Into<String>) -> MemoryTransport {
+        let (sender, receiver) = unbounded_channel();
+        self.routes.insert(id.into(), sender);
+        MemoryTransport {
+            routes: self.routes.clone(),
+            receiver,
+        }
+    }
+
+    /// Disconnects `id` from the network. Its input ends once it read the messages already
+    /// sent to it.
+    pub fn leave(&self, id: &str) {
+        self.routes.remove(id);
+    }
+}
+
+/// The transport of a member of a [`MemoryNetwork`].
+#[derive(Debug)]
+pub struct MemoryTransport {
+    routes: Routes,
+    receiver: UnboundedReceiver<String>,
+}
+
+impl Transport for MemoryTransport {
+    type Input = TransportInput;
+    type Output = TransportOutput;
+
+    fn open(self) -> io::Result<(Self::Input, Self::Output)> {
+        // Every member is routed to when it joins, so there's nothing to connect to
+        let output = TransportOutput::new(
+            self.routes,
+            Box::new(|dest| Err(NodeNotFound(dest.to_owned()))),
+        );
+        Ok((TransportInput::new(self.receiver), output))
+    }
+}
@@ -0,0 +1,342 @@
+//! Transports carrying a node's messages, so the same workload handlers can be served over
+//! stdin/stdout for Maelstrom, over sockets as a small cluster on one machine, or in memory
+//! for tests.
+pub mod memory;
+pub mod stdio;
+pub mod tcp;
+#[cfg(unix)]
+pub mod unix;
+
+use crate::error::MaelstromError;
+use serde::Deserialize;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt::{Debug, Formatter},
+    future::Future,
+    io::{self, Write},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf,
+        WriteHalf,
+    },
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
+
+/// Carries a node's messages, one JSON message per line, between the node and its peers.
+///
+/// Opening a transport gives the input/output pair served by an
+/// [`IoServer`](crate::server::stdio::IoServer), see
+/// [`IoServer::with_transport`](crate::server::stdio::IoServer::with_transport).
+pub trait Transport {
+    type Input: AsyncBufRead + Unpin;
+    type Output: Write;
+
+    /// Opens the transport. Transports with background tasks (e.g. to accept connections)
+    /// must be opened from within a tokio runtime.
+    fn open(self) -> io::Result<(Self::Input, Self::Output)>;
+}
+
+/// Sends the lines written to a node's output to the connection of their `dest`.
+pub type Link = UnboundedSender<String>;
+
+/// Opens a link to a destination the transport has no route to yet, failing with
+/// [`NodeNotFound`](MaelstromError::NodeNotFound) if it doesn't know how to reach it.
+type Connector = Box<dyn Fn(&str) -> Result<Link, MaelstromError> + Send>;
+
+/// The links to the destinations a transport already reached, by id.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Routes {
+    links: Arc<Mutex<HashMap<String, Link>>>,
+}
+
+impl Routes {
+    pub(crate) fn get(&self, id: &str) -> Option<Link> {
+        self.links.lock().ok()?.get(id).cloned()
+    }
+
+    pub(crate) fn insert(&self, id: String, link: Link) {
+        if let Ok(mut links) = self.links.lock() {
+            links.insert(id, link);
+        }
+    }
+
+    /// Adds a route to `id` unless there's already one.
+    pub(crate) fn insert_if_absent(&self, id: &str, link: impl FnOnce() -> Link) {
+        if let Ok(mut links) = self.links.lock() {
+            links.entry(id.to_owned()).or_insert_with(link);
+        }
+    }
+
+    pub(crate) fn remove(&self, id: &str) {
+        if let Ok(mut links) = self.links.lock() {
+            links.remove(id);
+        }
+    }
+}
+
+/// Input of a node, fed with the lines its transport received.
+///
+/// The input ends once every sender of its channel is dropped.
+#[derive(Debug)]
+pub struct TransportInput {
+    receiver: UnboundedReceiver<String>,
+    line: Vec<u8>,
+    consumed: usize,
+}
+
+impl TransportInput {
+    #[must_use]
+    pub fn new(receiver: UnboundedReceiver<String>) -> Self {
+        Self {
+            receiver,
+            line: Vec::new(),
+            consumed: 0,
+        }
+    }
+}
+
+impl AsyncRead for TransportInput {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let available = match self.as_mut().poll_fill_buf(cx) {
+            Poll::Ready(Ok(available)) => available,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        };
+        let size = available.len().min(buf.remaining());
+        buf.put_slice(&available[..size]);
+        self.consume(size);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncBufRead for TransportInput {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.consumed >= this.line.len() {
+            match this.receiver.poll_recv(cx) {
+                Poll::Ready(Some(line)) => {
+                    this.line = line.into_bytes();
+                    this.line.push(b'\n');
+                    this.consumed = 0;
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(&[])),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(&this.line[this.consumed..]))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.get_mut().consumed += amt;
+    }
+}
+
+/// Output of a node, sending every line written to it to the link of its `dest`.
+///
+/// Delivery is at most once, like on Maelstrom's network: a line without a route is dropped,
+/// and so are the lines queued on a connection that fails.
+pub struct TransportOutput {
+    routes: Routes,
+    connect: Connector,
+    pending: Vec<u8>,
+}
+
+impl TransportOutput {
+    pub(crate) fn new(routes: Routes, connect: Connector) -> Self {
+        Self {
+            routes,
+            connect,
+            pending: Vec::new(),
+        }
+    }
+
+    fn route(&self, mut message: String) {
+        let Some(dest) = peek(&message).and_then(|envelope| envelope.dest.map(Cow::into_owned))
+        else {
+            eprintln!("Dropping message without a destination: {message}");
+            return;
+        };
+        // Retry once on a fresh link, in case the route's connection closed since its last use
+        for _ in 0..2 {
+            let link = match self.routes.get(&dest) {
+                Some(link) => link,
+                None => match self.connect(&dest) {
+                    Ok(link) => link,
+                    Err(e) => {
+                        eprintln!("Dropping message, {e}: {message}");
+                        return;
+                    }
+                },
+            };
+            match link.send(message) {
+                Ok(()) => return,
+                Err(unsent) => {
+                    self.routes.remove(&dest);
+                    message = unsent.0;
+                }
+            }
+        }
+        eprintln!("Dropping message to unreachable destination {dest}: {message}");
+    }
+
+    fn connect(&self, dest: &str) -> Result<Link, MaelstromError> {
+        let link = (self.connect)(dest)?;
+        self.routes.insert(dest.to_owned(), link.clone());
+        Ok(link)
+    }
+}
+
+impl Debug for TransportOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TransportOutput")
+            .field("routes", &self.routes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Write for TransportOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        while let Some(end) = self.pending.iter().position(|byte| *byte == b'\n') {
+            let line = self.pending.drain(..=end).collect::<Vec<_>>();
+            self.route(String::from_utf8_lossy(&line[..end]).into_owned());
+        }
+        Ok(buf.len())
+    }
+
+    /// Lines are handed to their link as soon as they're complete, and each link flushes its
+    /// connection once it wrote every line queued on it.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct Envelope<'a> {
+    #[serde(borrow, default)]
+    src: Option<Cow<'a, str>>,
+    #[serde(borrow, default)]
+    dest: Option<Cow<'a, str>>,
+}
+
+fn peek(line: &str) -> Option<Envelope<'_>> {
+    serde_json::from_str(line).ok()
+}
+
+/// Spawns a task writing the lines sent on the returned link to the connection opened by
+/// `connect`. The task stops, closing the link, when the connection fails.
+pub(crate) fn spawn_link<F, W>(connect: F) -> Link
+where
+    F: Future<Output = io::Result<W>> + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (link, mut lines) = unbounded_channel::<String>();
+    tokio::spawn(async move {
+        let mut writer = match connect.await {
+            Ok(writer) => tokio::io::BufWriter::new(writer),
+            Err(e) => {
+                eprintln!("Unable to connect: {e}");
+                return;
+            }
+        };
+        while let Some(line) = lines.recv().await {
+            let mut result = write_line(&mut writer, &line).await;
+            // Write everything already queued before flushing
+            while let (Ok(()), Ok(line)) = (&result, lines.try_recv()) {
+                result = write_line(&mut writer, &line).await;
+            }
+            if let Err(e) = result.and(writer.flush().await) {
+                eprintln!("Connection failed: {e}");
+                return;
+            }
+        }
+    });
+    link
+}
+
+/// Spawns a link over the connection opened by `connect`, whose incoming lines (e.g. the
+/// replies of a peer that answers over the same connection) are read into the node's `input`.
+pub(crate) fn spawn_connection<F, S>(
+    connect: F,
+    input: UnboundedSender<String>,
+    routes: Routes,
+) -> Link
+where
+    F: Future<Output = io::Result<S>> + Send + 'static,
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    spawn_link(async move {
+        let (read, write) = tokio::io::split(connect.await?);
+        tokio::spawn(read_link(read, None::<WriteHalf<S>>, input, routes));
+        Ok(write)
+    })
+}
+
+async fn write_line<W: AsyncWrite + Unpin>(writer: &mut W, line: &str) -> io::Result<()> {
+    writer.write_all(line.as_bytes()).await?;
+    writer.write_all(b"\n").await
+}
+
+/// Listens for the connections of a transport's peers and clients, see [`accept`].
+pub(crate) trait Listener: Send + 'static {
+    type Read: AsyncRead + Unpin + Send + 'static;
+    type Write: AsyncWrite + Unpin + Send + 'static;
+
+    /// Accepts a connection, split into its reading and writing halves.
+    fn accept(&self) -> impl Future<Output = io::Result<(Self::Read, Self::Write)>> + Send;
+}
+
+/// Accepts connections until the node's input is dropped.
+pub(crate) async fn accept<L: Listener>(
+    listener: L,
+    input: UnboundedSender<String>,
+    routes: Routes,
+) {
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((read, write)) => {
+                    tokio::spawn(read_link(read, Some(write), input.clone(), routes.clone()));
+                }
+                Err(e) => eprintln!("Unable to accept connection: {e}"),
+            },
+            () = input.closed() => break,
+        }
+    }
+}
+
+/// Reads the lines of a connection into the node's `input`.
+///
+/// For accepted connections, `write` is their sending half: until there's another route to the
+/// sender of the first line, replies to it are sent back over this connection, so clients
+/// don't have to be reachable on their own.
+pub(crate) async fn read_link<R, W>(
+    read: R,
+    mut write: Option<W>,
+    input: UnboundedSender<String>,
+    routes: Routes,
+) where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if let Some(src) = peek(&line).and_then(|envelope| envelope.src) {
+            if let Some(write) = write.take() {
+                routes.insert_if_absent(&src, || spawn_link(async { Ok(write) }));
+            }
+        }
+        if input.send(line).is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,16 @@
+use crate::server::transport::Transport;
+use std::io::{self, Stdout};
+use tokio::io::{BufReader, Stdin};
+
+/// The transport Maelstrom runs nodes with: requests on stdin and replies on stdout.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdioTransport;
+
+impl Transport for StdioTransport {
+    type Input = BufReader<Stdin>;
+    type Output = Stdout;
+
+    fn open(self) -> io::Result<(Self::Input, Self::Output)> {
+        Ok((BufReader::new(tokio::io::stdin()), io::stdout()))
+    }
+}
@@ -0,0 +1,88 @@
+use crate::{
+    error::MaelstromError::NodeNotFound,
+    server::transport::{
+        accept, spawn_connection, Listener, Routes, Transport, TransportInput, TransportOutput,
+    },
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+};
+use tokio::{
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    sync::mpsc::unbounded_channel,
+};
+
+/// TCP transport for a node with a static list of peers.
+///
+/// The node connects to a peer the first time it sends it a message. Senders that aren't
+/// peers, like clients, get their replies over the connection their requests came in on.
+#[derive(Debug)]
+pub struct TcpTransport {
+    listener: std::net::TcpListener,
+    peers: HashMap<String, SocketAddr>,
+}
+
+impl TcpTransport {
+    /// Listens on `addr` for connections from peers and clients.
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            peers: HashMap::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Adds the peer `id`, listening on `addr`.
+    #[must_use]
+    pub fn peer(mut self, id: impl Into<String>, addr: SocketAddr) -> Self {
+        self.peers.insert(id.into(), addr);
+        self
+    }
+}
+
+impl Transport for TcpTransport {
+    type Input = TransportInput;
+    type Output = TransportOutput;
+
+    fn open(self) -> io::Result<(Self::Input, Self::Output)> {
+        let listener = TcpListener::from_std(self.listener)?;
+        let (input, receiver) = unbounded_channel();
+        let routes = Routes::default();
+        tokio::spawn(accept(listener, input.clone(), routes.clone()));
+
+        let peers = self.peers;
+        let connected = routes.clone();
+        let connect = move |dest: &str| {
+            let addr = *peers
+                .get(dest)
+                .ok_or_else(|| NodeNotFound(dest.to_owned()))?;
+            Ok(spawn_connection(
+                TcpStream::connect(addr),
+                input.clone(),
+                connected.clone(),
+            ))
+        };
+        let output = TransportOutput::new(routes, Box::new(connect));
+        Ok((TransportInput::new(receiver), output))
+    }
+}
+
+impl Listener for TcpListener {
+    type Read = OwnedReadHalf;
+    type Write = OwnedWriteHalf;
+
+    async fn accept(&self) -> io::Result<(Self::Read, Self::Write)> {
+        let (stream, _) = TcpListener::accept(self).await?;
+        Ok(stream.into_split())
+    }
+}
@@ -0,0 +1,98 @@
+use crate::{
+    error::MaelstromError::NodeNotFound,
+    server::transport::{
+        accept, spawn_connection, Listener, Routes, Transport, TransportInput, TransportOutput,
+    },
+};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixListener, UnixStream,
+    },
+    sync::mpsc::unbounded_channel,
+};
+
+/// Unix domain socket transport, for a cluster of nodes on the same machine.
+///
+/// Every member listens on `<dir>/<id>.sock`, and reaches another one by connecting to its
+/// socket in the same directory.
+#[derive(Clone, Debug)]
+pub struct UnixTransport {
+    dir: PathBuf,
+    id: String,
+}
+
+impl UnixTransport {
+    pub fn new(dir: impl Into<PathBuf>, id: impl Into<String>) -> Self {
+        Self {
+            dir: dir.into(),
+            id: id.into(),
+        }
+    }
+
+    /// The socket the member `id` listens on.
+    #[must_use]
+    pub fn socket_path(dir: &Path, id: &str) -> PathBuf {
+        dir.join(format!("{id}.sock"))
+    }
+}
+
+impl Transport for UnixTransport {
+    type Input = TransportInput;
+    type Output = TransportOutput;
+
+    fn open(self) -> io::Result<(Self::Input, Self::Output)> {
+        let path = Self::socket_path(&self.dir, &self.id);
+        // Remove the socket left behind by a previous run
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        let (input, receiver) = unbounded_channel();
+        let routes = Routes::default();
+        tokio::spawn(accept(listener, input.clone(), routes.clone()));
+
+        let dir = self.dir;
+        let connected = routes.clone();
+        let connect = move |dest: &str| {
+            // The destination comes from the message, so it must not reach out of `dir`
+            if !is_member_id(dest) {
+                return Err(NodeNotFound(dest.to_owned()));
+            }
+            let path = Self::socket_path(&dir, dest);
+            if !path.exists() {
+                return Err(NodeNotFound(dest.to_owned()));
+            }
+            Ok(spawn_connection(
+                UnixStream::connect(path),
+                input.clone(),
+                connected.clone(),
+            ))
+        };
+        let output = TransportOutput::new(routes, Box::new(connect));
+        Ok((TransportInput::new(receiver), output))
+    }
+}
+
+/// Whether `id` can name a member's socket: only ASCII letters, digits, `_` and `-`.
+fn is_member_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+impl Listener for UnixListener {
+    type Read = OwnedReadHalf;
+    type Write = OwnedWriteHalf;
+
+    async fn accept(&self) -> io::Result<(Self::Read, Self::Write)> {
+        let (stream, _) = UnixListener::accept(self).await?;
+        Ok(stream.into_split())
+    }
+}
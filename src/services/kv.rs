@@ -0,0 +1,309 @@
+use crate::{
+    error::MaelstromError::{self, KeyDoesNotExist, PreconditionFailed},
+    server::{
+        rpc::{backoff_delay, RpcClient, RpcPolicy},
+        stdio::SharedIoServerContext,
+    },
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::time::Duration;
+use tokio::time;
+
+/// Maelstrom's linearizable key-value store.
+pub const LIN_KV: &str = "lin-kv";
+/// Maelstrom's sequentially consistent key-value store.
+pub const SEQ_KV: &str = "seq-kv";
+/// Maelstrom's last-write-wins key-value store.
+pub const LWW_KV: &str = "lww-kv";
+
+/// Client of one of Maelstrom's key-value services, sending its requests as RPCs from the node.
+///
+/// Failed operations resolve to the error for their code, e.g.
+/// [`KeyDoesNotExist`](MaelstromError::KeyDoesNotExist) (20),
+/// [`KeyAlreadyExists`](MaelstromError::KeyAlreadyExists) (21) or
+/// [`PreconditionFailed`](MaelstromError::PreconditionFailed) (22).
+#[derive(Clone, Debug)]
+pub struct KvClient {
+    context: SharedIoServerContext,
+    service: String,
+    policy: RpcPolicy,
+    update_policy: UpdatePolicy,
+}
+
+/// Retries of [`KvClient::update`] when its `cas` conflicts with another update.
+///
+/// Each retry reads the key again, after an exponential backoff.
+#[derive(Clone, Debug)]
+pub struct UpdatePolicy {
+    max_attempts: usize,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    jitter: bool,
+}
+
+impl Default for UpdatePolicy {
+    /// Up to 10 attempts, backing off from 10ms to 200ms.
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            min_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(200),
+            jitter: true,
+        }
+    }
+}
+
+impl UpdatePolicy {
+    /// Sets how many times the key is read and `cas`ed at most, including the first attempt.
+    #[must_use]
+    pub fn max_attempts(mut self, attempts: usize) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    /// Sets the delay before the first retry, doubled on each retry up to `max`.
+    #[must_use]
+    pub fn backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_backoff = min;
+        self.max_backoff = max.max(min);
+        self
+    }
+
+    /// Picks each backoff at random between zero and its full delay (the default).
+    #[must_use]
+    pub fn jitter(mut self, enabled: bool) -> Self {
+        self.jitter = enabled;
+        self
+    }
+}
+
+/// The outcome of [`KvClient::update`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum UpdateOutcome<V> {
+    /// The key was set to this value.
+    Applied(V),
+    /// Every attempt conflicted with another update of the key, so the key wasn't changed.
+    Conflicted,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum KvRequest<'a, K, V> {
+    Read {
+        key: &'a K,
+    },
+    Write {
+        key: &'a K,
+        value: &'a V,
+    },
+    Cas {
+        key: &'a K,
+        from: &'a V,
+        to: &'a V,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+    },
+}
+
+#[derive(Deserialize)]
+struct ReadOk<V> {
+    value: V,
+}
+
+impl KvClient {
+    pub fn new(context: SharedIoServerContext, service: impl Into<String>) -> Self {
+        Self {
+            context,
+            service: service.into(),
+            policy: RpcPolicy::default(),
+            update_policy: UpdatePolicy::default(),
+        }
+    }
+
+    #[must_use]
+    pub fn lin_kv(context: SharedIoServerContext) -> Self {
+        Self::new(context, LIN_KV)
+    }
+
+    #[must_use]
+    pub fn seq_kv(context: SharedIoServerContext) -> Self {
+        Self::new(context, SEQ_KV)
+    }
+
+    #[must_use]
+    pub fn lww_kv(context: SharedIoServerContext) -> Self {
+        Self::new(context, LWW_KV)
+    }
+
+    /// Sets the timeout and retries of the requests (defaults to a single attempt timing out
+    /// after 1 second).
+    ///
+    /// Only indefinite errors are retried, but a `write` or `cas` that timed out may still
+    /// have been applied, so a retried `cas` can fail with `PreconditionFailed` after all.
+    /// [`KvClient::update`] never retries its `cas`.
+    #[must_use]
+    pub fn policy(mut self, policy: RpcPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Sets the retries of [`KvClient::update`] (defaults to [`UpdatePolicy::default`]).
+    #[must_use]
+    pub fn update_policy(mut self, policy: UpdatePolicy) -> Self {
+        self.update_policy = policy;
+        self
+    }
+
+    #[must_use]
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    #[must_use]
+    pub fn context(&self) -> &SharedIoServerContext {
+        &self.context
+    }
+
+    /// Reads the value of `key`.
+    pub async fn read<K, V>(&self, key: &K) -> Result<V, MaelstromError>
+    where
+        K: Serialize,
+        V: DeserializeOwned,
+    {
+        let reply = self.request::<K, ()>(KvRequest::Read { key }).await?;
+        Ok(serde_json::from_value::<ReadOk<V>>(reply)?.value)
+    }
+
+    /// Sets `key` to `value`.
+    pub async fn write<K, V>(&self, key: &K, value: &V) -> Result<(), MaelstromError>
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        self.request(KvRequest::Write { key, value }).await?;
+        Ok(())
+    }
+
+    /// Sets `key` to `to` if its value is `from`, failing with `PreconditionFailed` otherwise.
+    ///
+    /// With `create_if_not_exists`, a missing key is created with `to`, instead of failing with
+    /// `KeyDoesNotExist`.
+    pub async fn cas<K, V>(
+        &self,
+        key: &K,
+        from: &V,
+        to: &V,
+        create_if_not_exists: bool,
+    ) -> Result<(), MaelstromError>
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        self.request(KvRequest::Cas {
+            key,
+            from,
+            to,
+            create_if_not_exists,
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Sets `key` to `f` of its value, with a `cas` retried on conflicts with other updates.
+    ///
+    /// `f` is called again on each retry, with the value read then. A missing key fails with
+    /// `KeyDoesNotExist`, see [`KvClient::update_or`] to create it instead.
+    ///
+    /// The `cas` is sent once, whatever the client's [`policy`](KvClient::policy): when it
+    /// fails with an indefinite error like `Timeout`, it may have been applied, so the error
+    /// is returned instead of updating the key again.
+    pub async fn update<K, V, F>(&self, key: &K, f: F) -> Result<UpdateOutcome<V>, MaelstromError>
+    where
+        K: Serialize,
+        V: Serialize + DeserializeOwned + Clone,
+        F: FnMut(&V) -> V,
+    {
+        self.update_with(key, None, f).await
+    }
+
+    /// Like [`KvClient::update`], with a missing key updated as if its value was `default`.
+    pub async fn update_or<K, V, F>(
+        &self,
+        key: &K,
+        default: V,
+        f: F,
+    ) -> Result<UpdateOutcome<V>, MaelstromError>
+    where
+        K: Serialize,
+        V: Serialize + DeserializeOwned + Clone,
+        F: FnMut(&V) -> V,
+    {
+        self.update_with(key, Some(default), f).await
+    }
+
+    async fn update_with<K, V, F>(
+        &self,
+        key: &K,
+        default: Option<V>,
+        mut f: F,
+    ) -> Result<UpdateOutcome<V>, MaelstromError>
+    where
+        K: Serialize,
+        V: Serialize + DeserializeOwned + Clone,
+        F: FnMut(&V) -> V,
+    {
+        let policy = &self.update_policy;
+        let cas_policy = self.policy.clone().max_attempts(1);
+        for attempt in 0..policy.max_attempts {
+            if attempt > 0 {
+                let delay = backoff_delay(
+                    policy.min_backoff,
+                    policy.max_backoff,
+                    policy.jitter,
+                    attempt,
+                );
+                time::sleep(delay).await;
+            }
+            let (from, create_if_not_exists) = match (self.read(key).await, &default) {
+                (Ok(value), _) => (value, false),
+                (Err(KeyDoesNotExist(_)), Some(default)) => (default.clone(), true),
+                (Err(e), _) => return Err(e),
+            };
+            let to = f(&from);
+            let cas = KvRequest::Cas {
+                key,
+                from: &from,
+                to: &to,
+                create_if_not_exists,
+            };
+            match self.request_with(cas, &cas_policy).await {
+                Ok(_) => return Ok(UpdateOutcome::Applied(to)),
+                // The key changed since it was read (or was created meanwhile), so read it again
+                Err(PreconditionFailed(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(UpdateOutcome::Conflicted)
+    }
+
+    /// Sends `request` and returns the content of its reply.
+    async fn request<K: Serialize, V: Serialize>(
+        &self,
+        request: KvRequest<'_, K, V>,
+    ) -> Result<serde_json::Value, MaelstromError> {
+        self.request_with(request, &self.policy).await
+    }
+
+    /// Like [`KvClient::request`], with `policy` instead of the client's.
+    async fn request_with<K: Serialize, V: Serialize>(
+        &self,
+        request: KvRequest<'_, K, V>,
+        policy: &RpcPolicy,
+    ) -> Result<serde_json::Value, MaelstromError> {
+        let reply = self
+            .context
+            .rpc_with(self.service.clone(), request, policy)
+            .await?;
+        Ok(reply.body.content)
+    }
+}
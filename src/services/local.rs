@@ -0,0 +1,421 @@
+use crate::{
+    error::MaelstromError::{self, KeyDoesNotExist, PreconditionFailed},
+    message::{Body, Message},
+    server::transport::Transport,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    io::Write,
+};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// An in-process stand-in for one of Maelstrom's services, speaking the same JSON protocol.
+///
+/// Like an [`IoServer`](crate::server::stdio::IoServer), it serves an input/output pair, e.g.
+/// the transport it got by joining a
+/// [`MemoryNetwork`](crate::server::transport::memory::MemoryNetwork) as `lin-kv`, so
+/// workloads built on the services can be tested without Maelstrom.
+#[derive(Clone, Debug)]
+pub struct LocalService {
+    store: Store,
+}
+
+#[derive(Clone, Debug)]
+enum Store {
+    LinKv(LinMap),
+    SeqKv(SeqMap),
+    LwwKv(LwwMap),
+    LinTso(u64),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum ServiceRequest {
+    Read {
+        key: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    Ts,
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum ServiceResponse {
+    ReadOk { value: Value },
+    WriteOk,
+    CasOk,
+    TsOk { ts: u64 },
+}
+
+impl LocalService {
+    /// A linearizable key-value store, like `lin-kv`.
+    #[must_use]
+    pub fn lin_kv() -> Self {
+        Self::from_store(Store::LinKv(LinMap::default()))
+    }
+
+    /// A sequentially consistent key-value store, like `seq-kv`.
+    ///
+    /// Reads see the store as it was up to `staleness` writes ago, but never older than what
+    /// the same client already saw or wrote.
+    #[must_use]
+    pub fn seq_kv(staleness: u64) -> Self {
+        Self::from_store(Store::SeqKv(SeqMap {
+            staleness,
+            ..SeqMap::default()
+        }))
+    }
+
+    /// A last-write-wins key-value store, like `lww-kv`.
+    ///
+    /// Each client talks to one of `replicas` replicas, which gets the writes made on the
+    /// others once `lag` more requests were served. Concurrent writes are resolved by keeping
+    /// the last one.
+    #[must_use]
+    pub fn lww_kv(replicas: usize, lag: u64) -> Self {
+        Self::from_store(Store::LwwKv(LwwMap {
+            replicas: vec![HashMap::new(); replicas.max(1)],
+            lag,
+            ..LwwMap::default()
+        }))
+    }
+
+    /// A timestamp oracle handing out increasing timestamps from 0, like `lin-tso`.
+    #[must_use]
+    pub fn lin_tso() -> Self {
+        Self::from_store(Store::LinTso(0))
+    }
+
+    fn from_store(store: Store) -> Self {
+        Self { store }
+    }
+
+    /// Serves the requests read from `input` until it ends, writing the replies to `output`.
+    ///
+    /// Requests that can't be handled get an error reply, e.g. `key-does-not-exist` (20).
+    pub async fn serve<I, O>(mut self, input: I, mut output: O) -> Result<(), MaelstromError>
+    where
+        I: AsyncBufRead + Unpin,
+        O: Write,
+    {
+        let mut lines = input.lines();
+        while let Some(line) = lines.next_line().await? {
+            let request = match serde_json::from_str::<Message<Value>>(&line) {
+                Ok(request) => request,
+                Err(e) => {
+                    eprintln!("Unable to parse service request: {e}");
+                    continue;
+                }
+            };
+            writeln!(output, "{}", self.reply(request)?)?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Serves the requests received over `transport`, see [`LocalService::serve`].
+    pub async fn serve_transport<T: Transport>(self, transport: T) -> Result<(), MaelstromError> {
+        let (input, output) = transport.open()?;
+        self.serve(input, output).await
+    }
+
+    fn reply(&mut self, request: Message<Value>) -> Result<String, MaelstromError> {
+        let Message { src, dest, body } = request;
+        let response = serde_json::from_value::<ServiceRequest>(body.content)
+            .map_err(MaelstromError::MalformedRequest)
+            .and_then(|content| self.store.handle(&src, content));
+        Ok(match response {
+            Ok(response) => serde_json::to_string(&Message::new(
+                dest,
+                src,
+                Body::new(None, body.msg_id, response),
+            ))?,
+            Err(e) => serde_json::to_string(&e.to_error_reply(dest, src, body.msg_id))?,
+        })
+    }
+}
+
+impl Store {
+    fn handle(
+        &mut self,
+        client: &str,
+        request: ServiceRequest,
+    ) -> Result<ServiceResponse, MaelstromError> {
+        use ServiceRequest::{Cas, Read, Ts, Write};
+        match (self, request) {
+            (Store::LinTso(next), Ts) => {
+                let ts = *next;
+                *next += 1;
+                Ok(ServiceResponse::TsOk { ts })
+            }
+            (Store::LinTso(_), _) | (_, Ts) => Err(MaelstromError::NotSupported(
+                "operation not supported by this service".into(),
+            )),
+            (Store::LinKv(map), Read { key }) => map.read(&key),
+            (Store::LinKv(map), Write { key, value }) => Ok(map.write(&key, value)),
+            (
+                Store::LinKv(map),
+                Cas {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                },
+            ) => map.cas(&key, &from, to, create_if_not_exists),
+            (Store::SeqKv(map), Read { key }) => map.read(client, &key),
+            (Store::SeqKv(map), Write { key, value }) => Ok(map.write(client, &key, value)),
+            (
+                Store::SeqKv(map),
+                Cas {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                },
+            ) => map.cas(client, &key, &from, to, create_if_not_exists),
+            (Store::LwwKv(map), request) => map.handle(client, request),
+        }
+    }
+}
+
+/// The key under which a JSON key is stored, as JSON values can't be hashed.
+fn stored_key(key: &Value) -> String {
+    key.to_string()
+}
+
+/// Checks that a `cas` from `from` can be applied to the `current` value of `key`.
+fn check_cas(
+    key: &Value,
+    current: Option<&Value>,
+    from: &Value,
+    create_if_not_exists: bool,
+) -> Result<(), MaelstromError> {
+    match current {
+        None if create_if_not_exists => Ok(()),
+        None => Err(missing(key)),
+        Some(current) if current == from => Ok(()),
+        Some(current) => Err(PreconditionFailed(format!(
+            "expected {from}, but had {current}"
+        ))),
+    }
+}
+
+fn missing(key: &Value) -> MaelstromError {
+    KeyDoesNotExist(format!("key {key} does not exist"))
+}
+
+#[derive(Clone, Debug, Default)]
+struct LinMap {
+    values: HashMap<String, Value>,
+}
+
+impl LinMap {
+    fn read(&self, key: &Value) -> Result<ServiceResponse, MaelstromError> {
+        let value = self
+            .values
+            .get(&stored_key(key))
+            .ok_or_else(|| missing(key))?;
+        Ok(ServiceResponse::ReadOk {
+            value: value.clone(),
+        })
+    }
+
+    fn write(&mut self, key: &Value, value: Value) -> ServiceResponse {
+        self.values.insert(stored_key(key), value);
+        ServiceResponse::WriteOk
+    }
+
+    fn cas(
+        &mut self,
+        key: &Value,
+        from: &Value,
+        to: Value,
+        create_if_not_exists: bool,
+    ) -> Result<ServiceResponse, MaelstromError> {
+        check_cas(
+            key,
+            self.values.get(&stored_key(key)),
+            from,
+            create_if_not_exists,
+        )?;
+        self.values.insert(stored_key(key), to);
+        Ok(ServiceResponse::CasOk)
+    }
+}
+
+/// Keeps the values each key had over the last writes, so reads can be served from the past.
+#[derive(Clone, Debug, Default)]
+struct SeqMap {
+    staleness: u64,
+    /// The number of writes so far.
+    version: u64,
+    /// The values of each key, with the version that wrote them.
+    history: HashMap<String, VecDeque<(u64, Value)>>,
+    /// The latest version each client saw or wrote.
+    seen: HashMap<String, u64>,
+}
+
+impl SeqMap {
+    fn read(&mut self, client: &str, key: &Value) -> Result<ServiceResponse, MaelstromError> {
+        let seen = self.seen.entry(client.to_owned()).or_default();
+        let version = (*seen).max(self.version.saturating_sub(self.staleness));
+        *seen = version;
+        let value = self
+            .history
+            .get(&stored_key(key))
+            .and_then(|history| {
+                history
+                    .iter()
+                    .rev()
+                    .find(|(written, _)| *written <= version)
+            })
+            .ok_or_else(|| missing(key))?;
+        Ok(ServiceResponse::ReadOk {
+            value: value.1.clone(),
+        })
+    }
+
+    fn write(&mut self, client: &str, key: &Value, value: Value) -> ServiceResponse {
+        self.push(client, key, value);
+        ServiceResponse::WriteOk
+    }
+
+    /// Applies the `cas` to the latest value, which the client sees from now on, even if the
+    /// `cas` failed.
+    fn cas(
+        &mut self,
+        client: &str,
+        key: &Value,
+        from: &Value,
+        to: Value,
+        create_if_not_exists: bool,
+    ) -> Result<ServiceResponse, MaelstromError> {
+        self.seen.insert(client.to_owned(), self.version);
+        let current = self
+            .history
+            .get(&stored_key(key))
+            .and_then(VecDeque::back)
+            .map(|(_, value)| value);
+        check_cas(key, current, from, create_if_not_exists)?;
+        self.push(client, key, to);
+        Ok(ServiceResponse::CasOk)
+    }
+
+    fn push(&mut self, client: &str, key: &Value, value: Value) {
+        self.version += 1;
+        self.seen.insert(client.to_owned(), self.version);
+        let history = self.history.entry(stored_key(key)).or_default();
+        history.push_back((self.version, value));
+        // Only the last value written before the oldest version a read can see is still needed
+        let oldest = self.version.saturating_sub(self.staleness);
+        while history
+            .get(1)
+            .is_some_and(|(written, _)| *written <= oldest)
+        {
+            history.pop_front();
+        }
+    }
+}
+
+/// Replicas of the store, each holding the values with the timestamp of their write.
+#[derive(Clone, Debug, Default)]
+struct LwwMap {
+    replicas: Vec<HashMap<String, (u64, Value)>>,
+    lag: u64,
+    /// The number of requests so far.
+    requests: u64,
+    /// The timestamp of the last write.
+    clock: u64,
+    /// The writes to replicate, with the request after which they're replicated.
+    pending: VecDeque<(u64, String, u64, Value)>,
+}
+
+impl LwwMap {
+    fn handle(
+        &mut self,
+        client: &str,
+        request: ServiceRequest,
+    ) -> Result<ServiceResponse, MaelstromError> {
+        self.requests += 1;
+        self.replicate();
+        let replica = self.replica(client);
+        match request {
+            ServiceRequest::Read { key } => {
+                let (_, value) = self.replicas[replica]
+                    .get(&stored_key(&key))
+                    .ok_or_else(|| missing(&key))?;
+                Ok(ServiceResponse::ReadOk {
+                    value: value.clone(),
+                })
+            }
+            ServiceRequest::Write { key, value } => {
+                self.write(replica, &key, value);
+                Ok(ServiceResponse::WriteOk)
+            }
+            ServiceRequest::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => {
+                let current = self.replicas[replica]
+                    .get(&stored_key(&key))
+                    .map(|(_, value)| value);
+                check_cas(&key, current, &from, create_if_not_exists)?;
+                self.write(replica, &key, to);
+                Ok(ServiceResponse::CasOk)
+            }
+            ServiceRequest::Ts => Err(MaelstromError::NotSupported(
+                "operation not supported by this service".into(),
+            )),
+        }
+    }
+
+    /// The replica serving `client`, which is always the same one.
+    fn replica(&self, client: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        client.hash(&mut hasher);
+        usize::try_from(hasher.finish() % self.replicas.len() as u64).unwrap_or_default()
+    }
+
+    fn write(&mut self, replica: usize, key: &Value, value: Value) {
+        self.clock += 1;
+        let key = stored_key(key);
+        self.replicas[replica].insert(key.clone(), (self.clock, value.clone()));
+        self.pending
+            .push_back((self.requests + self.lag, key, self.clock, value));
+    }
+
+    /// Merges the writes that are due into every replica, keeping the last write of each key.
+    fn replicate(&mut self) {
+        while self
+            .pending
+            .front()
+            .is_some_and(|(due, ..)| *due < self.requests)
+        {
+            let Some((_, key, ts, value)) = self.pending.pop_front() else {
+                break;
+            };
+            for replica in &mut self.replicas {
+                let stored = replica.entry(key.clone()).or_insert((0, Value::Null));
+                if stored.0 < ts {
+                    *stored = (ts, value.clone());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,4 @@
+//! Clients for the services Maelstrom runs next to the nodes of a test.
+pub mod kv;
+pub mod local;
+pub mod tso;
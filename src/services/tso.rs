@@ -0,0 +1,135 @@
+use crate::{
+    error::MaelstromError,
+    server::{
+        rpc::{RpcClient, RpcPolicy},
+        stdio::SharedIoServerContext,
+    },
+};
+use futures::future::try_join_all;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::Mutex;
+
+/// Maelstrom's linearizable timestamp oracle.
+pub const LIN_TSO: &str = "lin-tso";
+
+/// Client of a timestamp oracle, handing out increasing timestamps.
+///
+/// [`TsoClient::lin_tso`] fetches them from Maelstrom's `lin-tso` service, while
+/// [`TsoClient::local`] counts them in memory, e.g. to test a workload without Maelstrom.
+#[derive(Clone, Debug)]
+pub struct TsoClient {
+    source: Source,
+    prefetched: Arc<Mutex<VecDeque<u64>>>,
+}
+
+#[derive(Clone, Debug)]
+enum Source {
+    Service {
+        context: SharedIoServerContext,
+        service: String,
+        policy: RpcPolicy,
+        prefetch: usize,
+    },
+    Local(Arc<AtomicU64>),
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum TsoRequest {
+    Ts,
+}
+
+#[derive(Deserialize)]
+struct TsOk {
+    ts: u64,
+}
+
+impl TsoClient {
+    pub fn new(context: SharedIoServerContext, service: impl Into<String>) -> Self {
+        Self::from_source(Source::Service {
+            context,
+            service: service.into(),
+            policy: RpcPolicy::default(),
+            prefetch: 1,
+        })
+    }
+
+    #[must_use]
+    pub fn lin_tso(context: SharedIoServerContext) -> Self {
+        Self::new(context, LIN_TSO)
+    }
+
+    /// A client counting timestamps in memory from 0, shared by its clones.
+    #[must_use]
+    pub fn local() -> Self {
+        Self::from_source(Source::Local(Arc::default()))
+    }
+
+    fn from_source(source: Source) -> Self {
+        Self {
+            source,
+            prefetched: Arc::default(),
+        }
+    }
+
+    /// Sets the timeout and retries of the requests (defaults to a single attempt timing out
+    /// after 1 second).
+    #[must_use]
+    pub fn policy(mut self, rpc_policy: RpcPolicy) -> Self {
+        if let Source::Service { policy, .. } = &mut self.source {
+            *policy = rpc_policy;
+        }
+        self
+    }
+
+    /// Fetches `count` timestamps at a time and hands them out in order (defaults to 1).
+    ///
+    /// A prefetched timestamp was issued when its batch was fetched, so it may be older than
+    /// timestamps fetched in the meantime by other nodes: only prefetch when that's acceptable.
+    #[must_use]
+    pub fn prefetch(mut self, count: usize) -> Self {
+        if let Source::Service { prefetch, .. } = &mut self.source {
+            *prefetch = count.max(1);
+        }
+        self
+    }
+
+    /// Gets the next timestamp, greater than the ones this client handed out before.
+    pub async fn timestamp(&self) -> Result<u64, MaelstromError> {
+        let (context, service, policy, prefetch) = match &self.source {
+            Source::Service {
+                context,
+                service,
+                policy,
+                prefetch,
+            } => (context, service, policy, *prefetch),
+            Source::Local(next) => return Ok(next.fetch_add(1, Ordering::SeqCst)),
+        };
+
+        // Concurrent callers wait for the batch being fetched instead of fetching their own
+        let mut prefetched = self.prefetched.lock().await;
+        if let Some(ts) = prefetched.pop_front() {
+            return Ok(ts);
+        }
+        let requests = (0..prefetch)
+            .map(|_| context.rpc_with(service.clone(), TsoRequest::Ts, policy))
+            .collect::<Vec<_>>();
+        let mut batch = try_join_all(requests)
+            .await?
+            .into_iter()
+            .map(|reply| Ok(serde_json::from_value::<TsOk>(reply.body.content)?.ts))
+            .collect::<Result<Vec<_>, MaelstromError>>()?;
+        batch.sort_unstable();
+        let mut batch = VecDeque::from(batch);
+        let ts = batch.pop_front().ok_or(MaelstromError::Crash)?;
+        *prefetched = batch;
+        Ok(ts)
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
     bin_tests::IoServerType::{Broadcast, Echo, GCounter, Generate},
-    helper::insert_init,
+    helper::serde_vec_to_string,
     init,
 };
 use derive_more::From;
@@ -34,7 +34,7 @@ impl Display for IoServerType {
 fn test_binaries() {
     // Run the example using `cargo run --example`
     for bin in [Echo, Broadcast, GCounter, Generate] {
-        let input = insert_init(Vec::from([init::REQUEST])).into_bytes();
+        let input = serde_vec_to_string(vec![init::REQUEST]).into_bytes();
         let mut output = Command::new("cargo")
             .arg("run")
             .arg("--example")
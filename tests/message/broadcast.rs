@@ -1,8 +1,16 @@
-use crate::helper::{can_serde, test_with_registered_service};
+use crate::{
+    helper::{
+        can_serde, delayed_eof_input, scripted_input, serde_vec_to_string,
+        test_with_registered_service,
+    },
+    init,
+};
 use maelstrom_lib::{
     message::broadcast::{Request, Response},
-    server::stdio::IoServerType,
+    server::stdio::{start_io_server, IoServerType},
 };
+use serde_json::Value;
+use std::time::Duration;
 
 pub const BROADCAST_REQUEST: &str = r#"
     {
@@ -83,7 +91,7 @@ const SYNC_RESPONSE: &str = r#"
         "body": {
             "in_reply_to": 44,
             "messages": [1,1000, 9001],
-            "msg_id": 4,
+            "msg_id": 5,
             "type": "read_ok"
         }
     }
@@ -120,7 +128,7 @@ const TOPOLOGY_RESPONSE: &str = r#"
 #[tokio::test]
 async fn broadcast_works_with_registered_service() {
     test_with_registered_service(
-        vec![BROADCAST_REQUEST],
+        vec![init::REQUEST, BROADCAST_REQUEST],
         BROADCAST_RESPONSE,
         IoServerType::Broadcast,
     )
@@ -129,13 +137,19 @@ async fn broadcast_works_with_registered_service() {
 
 #[tokio::test]
 async fn read_works_with_registered_service() {
-    let input = vec![BROADCAST_REQUEST, BROADCAST_REQUEST_2, READ_REQUEST];
+    let input = vec![
+        init::REQUEST,
+        BROADCAST_REQUEST,
+        BROADCAST_REQUEST_2,
+        READ_REQUEST,
+    ];
     test_with_registered_service(input, READ_RESPONSE, IoServerType::Broadcast).await;
 }
 
 #[tokio::test]
 async fn sync_works_with_registered_service() {
     let input = vec![
+        init::REQUEST,
         BROADCAST_REQUEST,
         BROADCAST_REQUEST_2,
         SYNC_REQUEST,
@@ -147,13 +161,61 @@ async fn sync_works_with_registered_service() {
 #[tokio::test]
 async fn topology_works_with_registered_service() {
     test_with_registered_service(
-        vec![TOPOLOGY_REQUEST],
+        vec![init::REQUEST, TOPOLOGY_REQUEST],
         TOPOLOGY_RESPONSE,
         IoServerType::Broadcast,
     )
     .await;
 }
 
+#[tokio::test]
+async fn gossip_is_written_to_server_output() {
+    let input = &serde_vec_to_string(vec![init::REQUEST, BROADCAST_REQUEST]);
+    let mut output = Vec::new();
+    let reader = delayed_eof_input(input, Duration::from_millis(500));
+    let _ = start_io_server(reader, &mut output, IoServerType::Broadcast).await;
+    let output = String::from_utf8(output).unwrap();
+    dbg!(&output);
+    assert!(output.contains("broadcast_ok"));
+    assert!(output.contains(r#""messages":[1000],"type":"sync""#));
+}
+
+#[tokio::test]
+async fn queued_gossip_is_flushed_on_shutdown() {
+    let input = &serde_vec_to_string(vec![init::REQUEST, BROADCAST_REQUEST]);
+    let mut output = Vec::new();
+    let _ = start_io_server(input.as_bytes(), &mut output, IoServerType::Broadcast).await;
+    let output = String::from_utf8(output).unwrap();
+    dbg!(&output);
+    assert!(output.contains(r#""messages":[1000],"type":"sync""#));
+}
+
+#[tokio::test(start_paused = true)]
+async fn acknowledged_gossip_is_not_resent() {
+    // The first round of gossip sends msg_id 3 to c1 and msg_id 4 to c3, but only c1 replies
+    let sync_ok = r#"{"src": "c1", "dest": "n1", "body": {"type": "sync_ok", "in_reply_to": 3}}"#;
+    let input = scripted_input(
+        vec![
+            (Duration::ZERO, init::REQUEST),
+            (Duration::ZERO, BROADCAST_REQUEST),
+            (Duration::from_millis(150), sync_ok),
+        ],
+        Duration::from_secs(2),
+    );
+    let mut output = Vec::new();
+    let _ = start_io_server(input, &mut output, IoServerType::Broadcast).await;
+    let gossip = String::from_utf8(output)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .filter(|message| message["body"]["type"] == "sync")
+        .map(|message| message["dest"].as_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+    dbg!(&gossip);
+    assert_eq!(1, gossip.iter().filter(|dest| *dest == "c1").count());
+    assert!(gossip.iter().filter(|dest| *dest == "c3").count() > 1);
+}
+
 #[tokio::test]
 async fn test_serde_broadcast() {
     can_serde::<Request>(BROADCAST_REQUEST);
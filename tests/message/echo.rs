@@ -1,4 +1,7 @@
-use crate::helper::{can_serde, test_with_registered_service};
+use crate::{
+    helper::{can_serde, test_with_registered_service},
+    init,
+};
 use maelstrom_lib::{
     message::echo::{Request, Response},
     server::stdio::IoServerType,
@@ -31,7 +34,7 @@ const RESPONSE: &str = r#"
 
 #[tokio::test]
 async fn works_with_registered_service() {
-    test_with_registered_service(vec![REQUEST], RESPONSE, IoServerType::Echo).await;
+    test_with_registered_service(vec![init::REQUEST, REQUEST], RESPONSE, IoServerType::Echo).await;
 }
 
 #[tokio::test]
@@ -1,9 +1,116 @@
+use crate::{echo, helper, init};
 use assert_matches::assert_matches;
 use maelstrom_lib::{
-    error::MaelstromError::{self, SerdeJsonError},
-    message::echo::Request,
+    error::{
+        ErrCode,
+        MaelstromError::{
+            self, Abort, Crash, Custom, KeyAlreadyExists, KeyDoesNotExist, NodeNotFound,
+            NotSupported, PreconditionFailed, SerdeJsonError, Timeout, TxnConflict,
+        },
+        MaelstromErrorBody,
+    },
+    message::{
+        echo::{Handler as EchoHandler, Request},
+        handler_fn, RawRequest, RequestType, WorkloadHandler,
+    },
+    server::stdio::{start_io_server, IoServer, IoServerType, SharedIoServerContext},
 };
-use serde_json::json;
+use serde_json::{json, Value};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use test_case::test_case;
+
+const NOT_INITIALIZED_RESPONSE: &str = r#"
+    {
+        "src": "n1",
+        "dest": "c2",
+        "body": {
+            "type": "error",
+            "in_reply_to": 42,
+            "code": 11,
+            "text": "Node got a valid message, but it was not the 'init' message."
+        }
+    }
+"#;
+
+const NOT_SUPPORTED_RESPONSE: &str = r#"
+    {
+        "src": "n1",
+        "dest": "c2",
+        "body": {
+            "type": "error",
+            "in_reply_to": 42,
+            "code": 10,
+            "text": "Not supported: echo is not supported"
+        }
+    }
+"#;
+
+const CRASHED_RESPONSE: &str = r#"
+    {
+        "src": "n1",
+        "dest": "c2",
+        "body": {
+            "type": "error",
+            "in_reply_to": 42,
+            "code": 13,
+            "text": "Unexpected error occurred"
+        }
+    }
+"#;
+
+const ABORTED_RESPONSE: &str = r#"
+    {
+        "src": "n1",
+        "dest": "c2",
+        "body": {
+            "type": "error",
+            "in_reply_to": 42,
+            "code": 14,
+            "text": "Aborted: lost the race"
+        }
+    }
+"#;
+
+#[test_case(Timeout("slow".into()), 0, false ; "timeout")]
+#[test_case(NodeNotFound("n9".into()), 1, true ; "node not found")]
+#[test_case(NotSupported("x".into()), 10, true ; "not supported")]
+#[test_case(MaelstromError::NodeNotInitialized, 11, true ; "temporarily unavailable")]
+#[test_case(MaelstromError::MissingMessageId, 12, true ; "malformed request")]
+#[test_case(Crash, 13, false ; "crash")]
+#[test_case(MaelstromError::OutboundClosed, 13, false ; "internal errors crash")]
+#[test_case(MaelstromError::NodeStopped, 13, false ; "stopped node crashes")]
+#[test_case(
+    SerdeJsonError(serde_json::from_str::<u64>("x").unwrap_err()), 13, false ;
+    "serialization errors crash"
+)]
+#[test_case(Abort("no".into()), 14, true ; "abort")]
+#[test_case(KeyDoesNotExist("x".into()), 20, true ; "key does not exist")]
+#[test_case(KeyAlreadyExists("x".into()), 21, true ; "key already exists")]
+#[test_case(PreconditionFailed("x".into()), 22, true ; "precondition failed")]
+#[test_case(TxnConflict("x".into()), 30, true ; "txn conflict")]
+#[test_case(Custom { code: 1000, text: "x".into() }, 1000, false ; "custom")]
+fn error_codes(error: MaelstromError, code: ErrCode, definite: bool) {
+    assert_eq!(code, error.code());
+    assert_eq!(definite, error.definite());
+}
+
+#[test]
+fn error_body_decodes_into_error() {
+    let body = MaelstromErrorBody::new("error".into(), 20, "no such key".into());
+    assert_matches!(MaelstromError::from(body), KeyDoesNotExist(text) if text == "no such key");
+
+    let body = MaelstromErrorBody::new("error".into(), 13, "n2 crashed".into());
+    assert_matches!(
+        MaelstromError::from(body),
+        Custom { code: 13, text } if text == "n2 crashed"
+    );
+
+    let body = MaelstromErrorBody::new("error".into(), 1234, "custom".into());
+    assert_matches!(MaelstromError::from(body), Custom { code: 1234, .. });
+}
 
 #[test]
 fn parse_json() {
@@ -43,3 +150,87 @@ fn parse_json() {
         .into();
     assert_matches!(actual_error, SerdeJsonError(..));
 }
+
+#[tokio::test]
+async fn error_reply_is_addressed_to_the_request() {
+    let input = &helper::serde_vec_to_string(vec![echo::REQUEST]);
+    let mut output = Vec::new();
+    let _ = start_io_server(input.as_bytes(), &mut output, IoServerType::Echo).await;
+    let output = helper::parse_json(&String::from_utf8(output).unwrap());
+    assert_eq!(helper::parse_json(NOT_INITIALIZED_RESPONSE), output);
+}
+
+#[tokio::test]
+async fn unparseable_request_is_not_replied_to() {
+    let input = "not json\n{\"src\": \"c1\"}\n";
+    let mut output = Vec::new();
+    let _ = start_io_server(input.as_bytes(), &mut output, IoServerType::Echo).await;
+    assert!(output.is_empty());
+}
+
+#[tokio::test]
+async fn handler_errors_are_replied_with_their_code() {
+    let input = &helper::serde_vec_to_string(vec![init::REQUEST, echo::REQUEST]);
+    let mut output = Vec::new();
+    let _ = IoServer::new(input.as_bytes(), &mut output)
+        .register(
+            RequestType::ECHO,
+            handler_fn(
+                |_context, _req: RawRequest| async move { Err(Abort("lost the race".into())) },
+            ),
+        )
+        .serve()
+        .await;
+    let output = helper::process_output(output, ABORTED_RESPONSE);
+    assert_eq!(helper::parse_json(ABORTED_RESPONSE), output);
+}
+
+#[tokio::test]
+async fn unregistered_request_type_is_not_supported() {
+    let input = &helper::serde_vec_to_string(vec![init::REQUEST, echo::REQUEST]);
+    let mut output = Vec::new();
+    let _ = start_io_server(input.as_bytes(), &mut output, IoServerType::Init).await;
+    let output = helper::process_output(output, NOT_SUPPORTED_RESPONSE);
+    assert_eq!(helper::parse_json(NOT_SUPPORTED_RESPONSE), output);
+}
+
+#[test_case(false ; "in the handler")]
+#[test_case(true ; "in a node command")]
+#[tokio::test]
+async fn panics_are_replied_as_crash_and_later_requests_are_served(in_node: bool) {
+    let input = &helper::serde_vec_to_string(vec![init::REQUEST, echo::REQUEST, echo::REQUEST]);
+    let panicked = Arc::new(AtomicBool::new(false));
+    let mut output = Vec::new();
+    let _ = IoServer::new(input.as_bytes(), &mut output)
+        .register(
+            RequestType::ECHO,
+            handler_fn(move |context: SharedIoServerContext, req: RawRequest| {
+                let panicked = panicked.clone();
+                async move {
+                    if !panicked.swap(true, Ordering::SeqCst) {
+                        if in_node {
+                            context.call(|_ctx| panic!("bad state")).await?;
+                        } else {
+                            panic!("bad request");
+                        }
+                    }
+                    EchoHandler.response(context, req).await
+                }
+            }),
+        )
+        .serve()
+        .await;
+    let output = String::from_utf8(output).unwrap();
+    dbg!(&output);
+    let bodies = output
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap()["body"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(3, bodies.len());
+    let crashed = helper::parse_json(CRASHED_RESPONSE);
+    assert_eq!(
+        serde_json::from_str::<Value>(&crashed).unwrap()["body"],
+        bodies[1]
+    );
+    assert_eq!("echo_ok", bodies[2]["type"]);
+}
@@ -1,8 +1,20 @@
-use crate::helper::{can_serde, test_with_registered_service};
+use crate::{
+    broadcast,
+    helper::{can_serde, serde_vec_to_string, test_with_registered_service},
+    init,
+    services::{start_service, Client},
+};
 use maelstrom_lib::{
     message::g_counter::{Request, Response},
-    server::stdio::IoServerType,
+    server::{
+        shutdown::ShutdownHandle,
+        stdio::{start_io_server_with, IoServer, IoServerType},
+        transport::memory::MemoryNetwork,
+    },
+    services::local::LocalService,
 };
+use serde_json::{json, Value};
+use std::collections::HashMap;
 
 pub const ADD_REQUEST: &str = r#"
     {
@@ -91,21 +103,84 @@ const SYNC_RESPONSE: &str = r#"
 
 #[tokio::test]
 async fn add_works_with_registered_service() {
-    test_with_registered_service(vec![ADD_REQUEST], ADD_RESPONSE, IoServerType::Gcounter).await;
+    test_with_registered_service(
+        vec![init::REQUEST, ADD_REQUEST],
+        ADD_RESPONSE,
+        IoServerType::Gcounter,
+    )
+    .await;
 }
 
 #[tokio::test]
 async fn read_works_with_registered_service() {
-    let input = vec![ADD_REQUEST, ADD_REQUEST_2, READ_REQUEST];
+    let input = vec![init::REQUEST, ADD_REQUEST, ADD_REQUEST_2, READ_REQUEST];
     test_with_registered_service(input, READ_RESPONSE, IoServerType::Gcounter).await;
 }
 
 #[tokio::test]
 async fn sync_works_with_registered_service() {
-    let input = vec![ADD_REQUEST, ADD_REQUEST_2, SYNC_REQUEST, READ_REQUEST];
+    let input = vec![
+        init::REQUEST,
+        ADD_REQUEST,
+        ADD_REQUEST_2,
+        SYNC_REQUEST,
+        READ_REQUEST,
+    ];
     test_with_registered_service(input, SYNC_RESPONSE, IoServerType::Gcounter).await;
 }
 
+/// Reads the counter of a node that also serves broadcast, whose `read` is routed to
+/// broadcast otherwise.
+const WORKLOAD_READ_REQUEST: &str = r#"
+    {
+        "src": "c1",
+        "dest": "n1",
+        "body": {
+            "type": "read",
+            "workload": "g-counter",
+            "msg_id": 15
+        }
+    }
+"#;
+
+#[tokio::test]
+async fn serves_broadcast_and_gcounter_side_by_side() {
+    let input = &serde_vec_to_string(vec![
+        init::REQUEST,
+        broadcast::BROADCAST_REQUEST,
+        ADD_REQUEST,
+        READ_REQUEST,
+        WORKLOAD_READ_REQUEST,
+    ]);
+    let mut output = Vec::new();
+    let workloads = [IoServerType::Broadcast, IoServerType::Gcounter];
+    let _ = start_io_server_with(input.as_bytes(), &mut output, &workloads).await;
+    let output = String::from_utf8(output).unwrap();
+    let reads = output
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap()["body"].clone())
+        .filter(|body| body["type"] == "read_ok")
+        .map(|body| (body["in_reply_to"].as_u64().unwrap(), body))
+        .collect::<HashMap<_, _>>();
+    // Broadcast was registered first, so it owns `read`
+    assert_eq!(json!([1000]), reads[&14]["messages"]);
+    assert_eq!(None, reads[&14].get("value"));
+    assert_eq!(json!(40), reads[&15]["value"]);
+    assert_eq!(None, reads[&15].get("messages"));
+}
+
+#[tokio::test]
+async fn ignores_a_workload_that_is_not_a_name() {
+    let read = READ_REQUEST.replace(r#""type": "read","#, r#""type": "read", "workload": 7,"#);
+    let input = &serde_vec_to_string(vec![init::REQUEST, ADD_REQUEST, &read]);
+    let mut output = Vec::new();
+    let workloads = [IoServerType::Broadcast, IoServerType::Gcounter];
+    let _ = start_io_server_with(input.as_bytes(), &mut output, &workloads).await;
+    let output = String::from_utf8(output).unwrap();
+    // Routed to broadcast, which owns `read`, like a read without a workload
+    assert!(output.contains(r#""messages":[]"#), "{output}");
+}
+
 #[tokio::test]
 async fn test_serde_gcounter() {
     can_serde::<Request>(ADD_REQUEST);
@@ -122,3 +197,124 @@ async fn test_serde_read() {
 async fn test_serde_sync() {
     can_serde::<Request>(SYNC_REQUEST);
 }
+
+/// Starts nodes n1 and n2 serving the g-counter stored in seq-kv, and initializes them.
+async fn start_seq_kv_cluster(network: &MemoryNetwork, client: &mut Client) -> Vec<ShutdownHandle> {
+    let mut nodes = Vec::new();
+    for node in ["n1", "n2"] {
+        let mut server = IoServer::with_transport(network.join(node)).unwrap();
+        server.workload(IoServerType::GcounterSeqKv);
+        nodes.push(server.shutdown_handle());
+        tokio::spawn(async move { server.serve().await });
+        let init = json!({"type": "init", "node_id": node, "node_ids": ["n1", "n2"]});
+        assert_eq!(json!("init_ok"), client.request(node, init).await["type"]);
+    }
+    nodes
+}
+
+#[tokio::test]
+async fn seq_kv_counter_is_shared_by_the_nodes() {
+    let network = MemoryNetwork::new();
+    // Reads may lag a few writes behind, which the nodes have to work around
+    start_service(&network, "seq-kv", LocalService::seq_kv(3));
+    let mut client = Client::new(&network, "c1");
+    let nodes = start_seq_kv_cluster(&network, &mut client).await;
+
+    assert_eq!(
+        json!(0),
+        client.request("n2", json!({"type": "read"})).await["value"]
+    );
+    for (node, delta) in [("n1", 40), ("n2", 2), ("n1", 0)] {
+        let add = json!({"type": "add", "delta": delta});
+        assert_eq!(json!("add_ok"), client.request(node, add).await["type"]);
+    }
+    // Each read sees every add acknowledged before it, whichever node served it
+    for node in ["n1", "n2"] {
+        assert_eq!(
+            json!(42),
+            client.request(node, json!({"type": "read"})).await["value"]
+        );
+    }
+    for node in nodes {
+        node.shutdown();
+    }
+}
+
+#[tokio::test]
+async fn seq_kv_counter_serves_concurrent_adds() {
+    let network = MemoryNetwork::new();
+    start_service(&network, "seq-kv", LocalService::seq_kv(3));
+    let mut client = Client::new(&network, "c1");
+    let nodes = start_seq_kv_cluster(&network, &mut client).await;
+
+    // Every add is sent before the first reply, so the nodes process them concurrently and
+    // their cas conflict with each other
+    for delta in 1..=6 {
+        let node = if delta % 2 == 0 { "n1" } else { "n2" };
+        client.send(node, json!({"type": "add", "delta": delta}));
+    }
+    for _ in 1..=6 {
+        assert_eq!(json!("add_ok"), client.receive().await["type"]);
+    }
+    for node in ["n1", "n2"] {
+        assert_eq!(
+            json!(21),
+            client.request(node, json!({"type": "read"})).await["value"]
+        );
+    }
+    for node in nodes {
+        node.shutdown();
+    }
+}
+
+#[tokio::test]
+async fn serves_both_g_counters_side_by_side() {
+    let network = MemoryNetwork::new();
+    start_service(&network, "seq-kv", LocalService::seq_kv(0));
+    let mut client = Client::new(&network, "c1");
+    let mut server = IoServer::with_transport(network.join("n1")).unwrap();
+    server
+        .workload(IoServerType::Gcounter)
+        .workload(IoServerType::GcounterSeqKv);
+    let node = server.shutdown_handle();
+    tokio::spawn(async move { server.serve().await });
+    let init = json!({"type": "init", "node_id": "n1", "node_ids": ["n1"]});
+    assert_eq!(json!("init_ok"), client.request("n1", init).await["type"]);
+
+    // Each counter only sees its own adds
+    let add = json!({"type": "add", "delta": 2});
+    client.request("n1", add).await;
+    let add = json!({"type": "add", "delta": 5, "workload": "g-counter-seq-kv"});
+    client.request("n1", add).await;
+    for (workload, value) in [("g-counter", 2), ("g-counter-seq-kv", 5)] {
+        let read = json!({"type": "read", "workload": workload});
+        assert_eq!(json!(value), client.request("n1", read).await["value"]);
+    }
+    // The gossiped counter was registered first, so it owns the plain types
+    let read = json!({"type": "read"});
+    assert_eq!(json!(2), client.request("n1", read).await["value"]);
+    node.shutdown();
+}
+
+#[tokio::test(start_paused = true)]
+async fn seq_kv_counter_fails_requests_while_seq_kv_is_unreachable() {
+    let network = MemoryNetwork::new();
+    let mut client = Client::new(&network, "c1");
+    let nodes = start_seq_kv_cluster(&network, &mut client).await;
+
+    // The request may or may not have been applied, so it fails with an indefinite error
+    let add = json!({"type": "add", "delta": 1});
+    let reply = client.request("n1", add.clone()).await;
+    assert_eq!(json!("error"), reply["type"]);
+    assert_eq!(json!(0), reply["code"]);
+
+    start_service(&network, "seq-kv", LocalService::seq_kv(0));
+    assert_eq!(json!("add_ok"), client.request("n1", add).await["type"]);
+    assert_eq!(
+        json!(1),
+        client.request("n2", json!({"type": "read"})).await["value"]
+    );
+    for node in nodes {
+        node.shutdown();
+    }
+}
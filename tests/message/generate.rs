@@ -1,4 +1,7 @@
-use crate::helper::{can_serde, insert_init, process_output};
+use crate::{
+    helper::{can_serde, process_output, serde_vec_to_string},
+    init,
+};
 use maelstrom_lib::{
     message::generate::{Request, Response},
     server::stdio::{start_io_server, IoServerType},
@@ -33,7 +36,7 @@ const RESPONSE: &str = r#"
 
 #[tokio::test]
 async fn works_with_registered_service() {
-    let input = &insert_init(vec![REQUEST]);
+    let input = &serde_vec_to_string(vec![init::REQUEST, REQUEST]);
     let mut output = Vec::new();
     let _ = start_io_server(input.as_bytes(), &mut output, IoServerType::Generate).await;
 
@@ -1,10 +1,24 @@
 use maelstrom_lib::{
-    message::Message,
-    server::stdio::{start_io_server, IoServerType},
+    error::MaelstromError,
+    message::{build_reply, handler_fn, Message, RawRequest, Request},
+    server::stdio::{start_io_server, IoServer, IoServerType, SharedIoServerContext},
 };
 use serde::{de::DeserializeOwned, Serialize};
 use serde_json::{from_str, to_string, Value};
-use std::{fmt::Debug, time::Duration};
+use std::{fmt::Debug, future::Future, time::Duration};
+use tokio::io::{duplex, AsyncBufRead, AsyncWriteExt, BufReader, DuplexStream};
+
+/// A `start` request from c1, for the tests serving it with [`serve_with_start`].
+pub const START_REQUEST: &str = r#"
+    {
+        "src": "c1",
+        "dest": "n1",
+        "body": {
+            "type": "start",
+            "msg_id": 1
+        }
+    }
+"#;
 
 pub fn parse_json(json: &str) -> String {
     from_str::<Value>(json)
@@ -17,7 +31,8 @@ pub fn process_output(output: Vec<u8>, expected: &str) -> String {
     let output = String::from_utf8(output).unwrap();
     dbg!(&output);
     let expected_output_value = from_str::<Message<Value>>(expected).unwrap().body.content;
-    // TODO: Fix MultiMessageBug so we don't have to hack tests to do initialization
+    // The output also holds the replies to the other requests (e.g. `init_ok`),
+    // so only keep the ones with the same type as the expected reply.
     let json: Vec<&str> = output.split('\n').collect::<Vec<&str>>();
     let json = &json
         .into_iter()
@@ -51,24 +66,77 @@ pub fn serde_vec_to_string(request: Vec<&str>) -> String {
         .join("\n")
 }
 
-pub fn insert_init(mut request: Vec<&str>) -> String {
-    request.insert(0, crate::init::REQUEST);
-    serde_vec_to_string(request)
-    // TODO: Fix MultiMessageBug so we don't have to hack tests to do initialization
-}
-
 pub async fn test_with_registered_service(
     request: Vec<&str>,
     response: &str,
     io_type: IoServerType,
 ) {
-    let input = &insert_init(request);
+    let input = &serde_vec_to_string(request);
     dbg!(&input);
     let expected_output = &parse_json(response);
     let mut output = Vec::new();
     let _ = start_io_server(input.as_bytes(), &mut output, io_type).await;
-    tokio::time::sleep(Duration::from_secs(2)).await;
     let output = &process_output(output, expected_output);
     dbg!(&input, &output, response, expected_output,);
     assert_eq!(expected_output, output);
 }
+
+/// Returns an input that yields `input` right away, but only reports the end of the input
+/// after `delay`, giving background tasks a chance to run while the server is still serving.
+pub fn delayed_eof_input(input: &str, delay: Duration) -> BufReader<DuplexStream> {
+    let (mut writer, reader) = duplex(input.len() + 1);
+    let input = format!("{input}\n");
+    tokio::spawn(async move {
+        let _ = writer.write_all(input.as_bytes()).await;
+        tokio::time::sleep(delay).await;
+    });
+    BufReader::new(reader)
+}
+
+/// Returns an input that yields each request after waiting for its delay, e.g. so a reply only
+/// arrives once the request it answers was sent. The input ends after `eof_delay`.
+pub fn scripted_input(
+    requests: Vec<(Duration, &str)>,
+    eof_delay: Duration,
+) -> BufReader<DuplexStream> {
+    let (mut writer, reader) = duplex(1024);
+    let requests = requests
+        .into_iter()
+        .map(|(delay, request)| (delay, format!("{}\n", parse_json(request))))
+        .collect::<Vec<_>>();
+    tokio::spawn(async move {
+        for (delay, request) in requests {
+            tokio::time::sleep(delay).await;
+            let _ = writer.write_all(request.as_bytes()).await;
+        }
+        tokio::time::sleep(eof_delay).await;
+    });
+    BufReader::new(reader)
+}
+
+/// Serves `input` with `start` requests handled by `start`, on a server set up by `configure`
+/// (e.g. to register other handlers). Returns everything the server wrote.
+pub async fn serve_with_start<I, C, F, Fut>(input: I, configure: C, start: F) -> Vec<u8>
+where
+    I: AsyncBufRead + Unpin,
+    C: FnOnce(&mut IoServer<I, &mut Vec<u8>>),
+    F: Fn(SharedIoServerContext, RawRequest) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<String, MaelstromError>> + Send + 'static,
+{
+    let mut output = Vec::new();
+    let mut server = IoServer::new(input, &mut output);
+    configure(&mut server);
+    let _ = server.register("start", handler_fn(start)).serve().await;
+    drop(server);
+    output
+}
+
+/// Serializes the reply to `req` with `body`, as returned by a handler.
+pub async fn reply_to(
+    context: &SharedIoServerContext,
+    req: &RawRequest,
+    body: Value,
+) -> Result<String, MaelstromError> {
+    let req: Request<Value> = req.deserialize()?;
+    build_reply(req, context, body).await?.serde_to_string()
+}
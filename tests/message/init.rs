@@ -1,8 +1,16 @@
-use crate::helper::{can_serde, test_with_registered_service};
+use crate::{
+    echo,
+    helper::{can_serde, scripted_input, serde_vec_to_string, test_with_registered_service},
+};
 use maelstrom_lib::{
-    message::init::{Request, Response},
-    server::stdio::IoServerType,
+    message::{
+        echo::Handler as EchoHandler,
+        init::{Request, Response},
+        RequestType,
+    },
+    server::stdio::{start_io_server, IoServer, IoServerType, MAX_BUFFERED_REQUESTS},
 };
+use std::time::Duration;
 
 pub const REQUEST: &str = r#"
     {
@@ -31,7 +39,7 @@ const RESPONSE: &str = r#"
 
 #[tokio::test]
 async fn works_with_registered_service() {
-    test_with_registered_service(vec![], RESPONSE, IoServerType::Init).await;
+    test_with_registered_service(vec![REQUEST], RESPONSE, IoServerType::Init).await;
 }
 
 #[tokio::test]
@@ -39,3 +47,69 @@ async fn test_serde() {
     can_serde::<Request>(REQUEST);
     can_serde::<Response>(RESPONSE);
 }
+
+#[tokio::test]
+async fn rejects_requests_before_init() {
+    let input = &serde_vec_to_string(vec![echo::REQUEST, REQUEST, echo::REQUEST]);
+    let mut output = Vec::new();
+    let _ = start_io_server(input.as_bytes(), &mut output, IoServerType::Echo).await;
+    let output = String::from_utf8(output).unwrap();
+    dbg!(&output);
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(3, lines.len());
+    assert!(lines[0].contains("it was not the 'init' message"));
+    assert!(lines[1].contains("init_ok"));
+    assert!(lines[2].contains("echo_ok"));
+}
+
+#[tokio::test]
+async fn rejects_duplicate_init() {
+    let input = &serde_vec_to_string(vec![REQUEST, REQUEST]);
+    let mut output = Vec::new();
+    let _ = start_io_server(input.as_bytes(), &mut output, IoServerType::Init).await;
+    let output = String::from_utf8(output).unwrap();
+    dbg!(&output);
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(2, lines.len());
+    assert!(lines[0].contains("init_ok"));
+    assert!(lines[1].contains("Node is already initialized"));
+}
+
+#[tokio::test]
+async fn buffers_requests_until_init() {
+    let input = &serde_vec_to_string(vec![echo::REQUEST, REQUEST]);
+    let mut output = Vec::new();
+    let _ = IoServer::new(input.as_bytes(), &mut output)
+        .buffer_until_init(true)
+        .register(RequestType::ECHO, EchoHandler)
+        .serve()
+        .await;
+    let output = String::from_utf8(output).unwrap();
+    dbg!(&output);
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(2, lines.len());
+    assert!(lines[0].contains("init_ok"));
+    assert!(lines[1].contains("echo_ok"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn rejects_requests_past_the_init_buffer() {
+    let mut requests = vec![(Duration::ZERO, echo::REQUEST); MAX_BUFFERED_REQUESTS + 1];
+    requests.push((Duration::from_millis(100), REQUEST));
+    let input = scripted_input(requests, Duration::from_millis(100));
+    let mut output = Vec::new();
+    let _ = IoServer::new(input, &mut output)
+        .buffer_until_init(true)
+        .register(RequestType::ECHO, EchoHandler)
+        .serve()
+        .await;
+    let output = String::from_utf8(output).unwrap();
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(MAX_BUFFERED_REQUESTS + 2, lines.len());
+    assert!(lines[0].contains(r#""code":11"#), "{}", lines[0]);
+    assert!(lines[1].contains("init_ok"));
+    assert_eq!(
+        MAX_BUFFERED_REQUESTS,
+        lines.iter().filter(|line| line.contains("echo_ok")).count()
+    );
+}
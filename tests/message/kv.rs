@@ -0,0 +1,257 @@
+use crate::{
+    helper::{reply_to, scripted_input, serve_with_start, START_REQUEST},
+    init,
+    services::{start_service, Client},
+};
+use futures::future::join_all;
+use maelstrom_lib::{
+    message::{handler_fn, Message, RawRequest},
+    server::{
+        rpc::RpcPolicy,
+        shutdown::ShutdownHandle,
+        stdio::{IoServer, SharedIoServerContext},
+        transport::memory::MemoryNetwork,
+    },
+    services::{
+        kv::{KvClient, UpdateOutcome, UpdatePolicy},
+        local::LocalService,
+    },
+};
+use serde_json::{json, Value};
+use std::time::Duration;
+use test_case::test_case;
+
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Read,
+    Write,
+    Cas {
+        create_if_not_exists: bool,
+    },
+    /// Increments the key, with RPCs timing out after 200ms and retried once.
+    Update,
+}
+
+/// Serves a `start` request that runs `op` against lin-kv, which answers with `reply` (the
+/// RPC uses msg_id 2, after `init_ok`). Returns the request sent to lin-kv and the `start`
+/// reply.
+async fn start_with_reply(op: Op, reply: Value) -> (Value, Value) {
+    let (sent, response) = start_with_replies(op, vec![reply]).await;
+    (sent[0].clone(), response)
+}
+
+/// Like [`start_with_reply`], with lin-kv's `replies` arriving 100ms then 300ms apart.
+/// Returns every request sent to lin-kv.
+async fn start_with_replies(op: Op, replies: Vec<Value>) -> (Vec<Value>, Value) {
+    let replies = replies
+        .into_iter()
+        .map(|reply| json!({"src": "lin-kv", "dest": "n1", "body": reply}).to_string())
+        .collect::<Vec<_>>();
+    let mut input = vec![
+        (Duration::ZERO, init::REQUEST),
+        (Duration::ZERO, START_REQUEST),
+    ];
+    for (i, reply) in replies.iter().enumerate() {
+        let delay = if i == 0 { 100 } else { 300 };
+        input.push((Duration::from_millis(delay), reply.as_str()));
+    }
+    let input = scripted_input(input, Duration::from_secs(1));
+    let output = serve_with_start(
+        input,
+        |_| {},
+        move |context: SharedIoServerContext, req| async move {
+            let kv = KvClient::lin_kv(context.clone());
+            let value = match op {
+                Op::Read => kv.read::<_, u64>(&"x").await?,
+                Op::Write => kv.write(&"x", &1).await.map(|()| 0)?,
+                Op::Cas {
+                    create_if_not_exists,
+                } => kv
+                    .cas(&"x", &1, &2, create_if_not_exists)
+                    .await
+                    .map(|()| 0)?,
+                Op::Update => {
+                    let policy = RpcPolicy::default()
+                        .timeout(Duration::from_millis(200))
+                        .max_attempts(2)
+                        .backoff(Duration::from_millis(1), Duration::from_millis(1));
+                    match kv
+                        .policy(policy)
+                        .update(&"x", |value: &u64| value + 1)
+                        .await?
+                    {
+                        UpdateOutcome::Applied(value) => value,
+                        UpdateOutcome::Conflicted => 0,
+                    }
+                }
+            };
+            reply_to(&context, &req, json!({"type": "start_ok", "value": value})).await
+        },
+    )
+    .await;
+    let output = String::from_utf8(output).unwrap();
+    let messages: Vec<Value> = output
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let sent = messages
+        .iter()
+        .filter(|message| message["dest"] == "lin-kv")
+        .map(|message| message["body"].clone())
+        .collect::<Vec<_>>();
+    assert!(!sent.is_empty(), "no request sent to lin-kv");
+    let response = messages
+        .iter()
+        .find(|message| message["dest"] == "c1")
+        .expect("no reply to the start request");
+    (sent, response["body"].clone())
+}
+
+#[test_case(Op::Read, json!({"type": "read", "msg_id": 2, "key": "x"}); "read")]
+#[test_case(Op::Write, json!({"type": "write", "msg_id": 2, "key": "x", "value": 1}); "write")]
+#[test_case(
+    Op::Cas { create_if_not_exists: false },
+    json!({"type": "cas", "msg_id": 2, "key": "x", "from": 1, "to": 2});
+    "cas"
+)]
+#[test_case(
+    Op::Cas { create_if_not_exists: true },
+    json!({"type": "cas", "msg_id": 2, "key": "x", "from": 1, "to": 2, "create_if_not_exists": true});
+    "cas creating the key"
+)]
+#[tokio::test(start_paused = true)]
+async fn sends_kv_requests(op: Op, expected: Value) {
+    let (sent, _) =
+        start_with_reply(op, json!({"type": "error", "in_reply_to": 2, "code": 13})).await;
+    assert_eq!(expected, sent);
+}
+
+#[tokio::test(start_paused = true)]
+async fn read_resolves_to_the_value() {
+    let reply = json!({"type": "read_ok", "in_reply_to": 2, "value": 42});
+    let output = start_with_reply(Op::Read, reply).await;
+    assert_eq!(
+        json!({"type": "start_ok", "msg_id": 3, "in_reply_to": 1, "value": 42}),
+        output.1
+    );
+}
+
+#[test_case(Op::Write, json!({"type": "write_ok", "in_reply_to": 2}); "write")]
+#[test_case(Op::Cas { create_if_not_exists: false }, json!({"type": "cas_ok", "in_reply_to": 2}); "cas")]
+#[tokio::test(start_paused = true)]
+async fn updates_resolve_on_their_ok_reply(op: Op, reply: Value) {
+    let (_, response) = start_with_reply(op, reply).await;
+    assert_eq!(
+        json!({"type": "start_ok", "msg_id": 3, "in_reply_to": 1, "value": 0}),
+        response
+    );
+}
+
+#[test_case(Op::Read, 20, "Key does not exist: not found"; "missing key")]
+#[test_case(Op::Cas { create_if_not_exists: true }, 21, "Key already exists: not found"; "existing key")]
+#[test_case(Op::Cas { create_if_not_exists: false }, 22, "Precondition failed: not found"; "cas mismatch")]
+#[tokio::test(start_paused = true)]
+async fn kv_errors_are_resolved_to_their_code(op: Op, code: u64, text: &str) {
+    let reply = json!({"type": "error", "in_reply_to": 2, "code": code, "text": "not found"});
+    let (_, response) = start_with_reply(op, reply).await;
+    assert_eq!(
+        json!({"type": "error", "in_reply_to": 1, "code": code, "text": text}),
+        response
+    );
+}
+
+/// Starts n1 next to a lin-kv stand-in. Its `update` requests run `count` concurrent
+/// increments of the counter, with up to `attempts` attempts each, creating it if `create`.
+async fn start_updating_node(network: &MemoryNetwork, client: &mut Client) -> ShutdownHandle {
+    start_service(network, "lin-kv", LocalService::lin_kv());
+    let mut node = IoServer::with_transport(network.join("n1")).unwrap();
+    node.register(
+        "update",
+        handler_fn(
+            |context: SharedIoServerContext, req: RawRequest| async move {
+                let update: Message<Value> = req.deserialize()?;
+                let content = &update.body.content;
+                let policy = UpdatePolicy::default()
+                    .max_attempts(content["attempts"].as_u64().unwrap_or(1) as usize)
+                    .backoff(Duration::from_millis(1), Duration::from_millis(5));
+                let kv = KvClient::lin_kv(context).update_policy(policy);
+                let updates = (0..content["count"].as_u64().unwrap_or(1)).map(|_| async {
+                    if content["create"] == json!(true) {
+                        kv.update_or(&"counter", 0, |value: &u64| value + 1).await
+                    } else {
+                        kv.update(&"counter", |value: &u64| value + 1).await
+                    }
+                });
+                let mut applied = 0;
+                for outcome in join_all(updates).await {
+                    if let UpdateOutcome::Applied(_) = outcome? {
+                        applied += 1;
+                    }
+                }
+                let value = kv.read::<_, u64>(&"counter").await?;
+                let body = json!({"type": "update_ok", "applied": applied, "value": value});
+                reply_to(kv.context(), &req, body).await
+            },
+        ),
+    );
+    let shutdown = node.shutdown_handle();
+    tokio::spawn(async move { node.serve().await });
+    let init = json!({"type": "init", "node_id": "n1", "node_ids": ["n1"]});
+    assert_eq!(json!("init_ok"), client.request("n1", init).await["type"]);
+    shutdown
+}
+
+#[tokio::test]
+async fn update_fails_on_a_missing_key_without_default() {
+    let network = MemoryNetwork::new();
+    let mut client = Client::new(&network, "c1");
+    let node = start_updating_node(&network, &mut client).await;
+    let update = json!({"type": "update"});
+    assert_eq!(json!(20), client.request("n1", update).await["code"]);
+    node.shutdown();
+}
+
+#[tokio::test]
+async fn update_retries_conflicting_updates() {
+    let network = MemoryNetwork::new();
+    let mut client = Client::new(&network, "c1");
+    let node = start_updating_node(&network, &mut client).await;
+    let update = json!({"type": "update", "count": 5, "attempts": 10, "create": true});
+    let response = client.request("n1", update).await;
+    assert_eq!(
+        (&json!(5), &json!(5)),
+        (&response["applied"], &response["value"])
+    );
+    // Once the key exists, it's updated without a default
+    let update = json!({"type": "update", "count": 1});
+    assert_eq!(json!(6), client.request("n1", update).await["value"]);
+    node.shutdown();
+}
+
+#[tokio::test]
+async fn update_reports_updates_that_conflicted_too_many_times() {
+    let network = MemoryNetwork::new();
+    let mut client = Client::new(&network, "c1");
+    let node = start_updating_node(&network, &mut client).await;
+    // All the updates read the counter before the first one is applied
+    let update = json!({"type": "update", "count": 5, "attempts": 1, "create": true});
+    let response = client.request("n1", update).await;
+    assert_eq!(
+        (&json!(1), &json!(1)),
+        (&response["applied"], &response["value"])
+    );
+    node.shutdown();
+}
+
+#[tokio::test(start_paused = true)]
+async fn update_does_not_resend_a_cas_that_timed_out() {
+    // The cas (msg_id 3) is applied but its reply is lost. A resent cas (msg_id 4) would fail
+    // since the key changed, and the update would then increment the key a second time.
+    let replies = vec![
+        json!({"type": "read_ok", "in_reply_to": 2, "value": 1}),
+        json!({"type": "error", "in_reply_to": 4, "code": 22, "text": "value changed"}),
+    ];
+    let (sent, response) = start_with_replies(Op::Update, replies).await;
+    assert_eq!(1, sent.iter().filter(|body| body["type"] == "cas").count());
+    assert_eq!(json!(0), response["code"], "{response}");
+}
@@ -2,10 +2,16 @@ extern crate core;
 
 pub mod bin_tests;
 mod broadcast;
-mod echo;
+pub mod echo;
 mod error;
 mod g_counter;
 mod generate;
 pub mod helper;
 pub mod init;
+mod kv;
+mod rpc;
+pub mod services;
 mod stdin;
+mod timer;
+mod transport;
+mod tso;
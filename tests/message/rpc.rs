@@ -0,0 +1,316 @@
+use crate::{
+    helper::{
+        parse_json, process_output, reply_to, scripted_input, serve_with_start, START_REQUEST,
+    },
+    init,
+};
+use maelstrom_lib::server::{
+    rpc::{RpcClient, RpcPolicy},
+    stdio::{IoServer, SharedIoServerContext},
+};
+use serde_json::json;
+use std::time::Duration;
+use test_case::test_case;
+use tokio::io::{BufReader, DuplexStream};
+
+// The `init_ok` reply uses msg_id 1, so the RPC sent to n2 uses msg_id 2
+const PONG_REPLY: &str = r#"
+    {
+        "src": "n2",
+        "dest": "n1",
+        "body": {
+            "type": "pong",
+            "in_reply_to": 2,
+            "answer": 42
+        }
+    }
+"#;
+
+const KEY_ERROR_REPLY: &str = r#"
+    {
+        "src": "n2",
+        "dest": "n1",
+        "body": {
+            "type": "error",
+            "in_reply_to": 2,
+            "code": 20,
+            "text": "no such key"
+        }
+    }
+"#;
+
+const START_OK_RESPONSE: &str = r#"
+    {
+        "src": "n1",
+        "dest": "c1",
+        "body": {
+            "type": "start_ok",
+            "msg_id": 3,
+            "in_reply_to": 1,
+            "answer": 42
+        }
+    }
+"#;
+
+const START_ERROR_RESPONSE: &str = r#"
+    {
+        "src": "n1",
+        "dest": "c1",
+        "body": {
+            "type": "error",
+            "in_reply_to": 1,
+            "code": 20,
+            "text": "Key does not exist: no such key"
+        }
+    }
+"#;
+
+const CRASH_REPLY: &str = r#"
+    {
+        "src": "n2",
+        "dest": "n1",
+        "body": {
+            "type": "error",
+            "in_reply_to": 2,
+            "code": 13,
+            "text": "crashed"
+        }
+    }
+"#;
+
+const START_TIMEOUT_RESPONSE: &str = r#"
+    {
+        "src": "n1",
+        "dest": "c1",
+        "body": {
+            "type": "error",
+            "in_reply_to": 1,
+            "code": 0,
+            "text": "Timeout: request timed out"
+        }
+    }
+"#;
+
+/// Serves a `start` request that pings n2 and replies with its answer, while n2's `reply`
+/// arrives after the ping was sent.
+async fn start_with_reply(reply: &str) -> Vec<u8> {
+    start_with_replies(vec![(Duration::from_millis(100), reply)], None).await
+}
+
+/// Serves a `start` request that pings n2, with `policy` if any, while n2's `replies` arrive
+/// after the given delays.
+async fn start_with_replies(replies: Vec<(Duration, &str)>, policy: Option<RpcPolicy>) -> Vec<u8> {
+    let mut input = vec![
+        (Duration::ZERO, init::REQUEST),
+        (Duration::ZERO, START_REQUEST),
+    ];
+    input.extend(replies);
+    serve_starts(input, policy, |_| {}).await
+}
+
+/// Serves the `start` requests of `input` that each ping n2 like [`start_with_replies`], on
+/// a server set up by `configure`.
+async fn serve_starts(
+    input: Vec<(Duration, &str)>,
+    policy: Option<RpcPolicy>,
+    configure: impl FnOnce(&mut IoServer<BufReader<DuplexStream>, &mut Vec<u8>>),
+) -> Vec<u8> {
+    let input = scripted_input(input, Duration::from_secs(1));
+    serve_with_start(
+        input,
+        configure,
+        move |context: SharedIoServerContext, req| {
+            let policy = policy.clone();
+            async move {
+                let ping = json!({"type": "ping"});
+                let reply = match policy {
+                    Some(policy) => context.rpc_with("n2", ping, &policy).await?,
+                    None => context.rpc("n2", ping).await?,
+                };
+                let answer = &reply.body.content["answer"];
+                reply_to(
+                    &context,
+                    &req,
+                    json!({"type": "start_ok", "answer": answer}),
+                )
+                .await
+            }
+        },
+    )
+    .await
+}
+
+#[tokio::test(start_paused = true)]
+async fn rpc_resolves_to_its_reply() {
+    let output = start_with_reply(PONG_REPLY).await;
+    dbg!(String::from_utf8_lossy(&output));
+    assert_eq!(
+        parse_json(START_OK_RESPONSE),
+        process_output(output, START_OK_RESPONSE)
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn rpc_resolves_error_replies_to_errors() {
+    let output = start_with_reply(KEY_ERROR_REPLY).await;
+    assert_eq!(
+        parse_json(START_ERROR_RESPONSE),
+        process_output(output, START_ERROR_RESPONSE)
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn reply_nobody_waits_for_is_dropped() {
+    let input = scripted_input(
+        vec![
+            (Duration::ZERO, init::REQUEST),
+            (Duration::ZERO, PONG_REPLY),
+        ],
+        Duration::from_millis(100),
+    );
+    let mut output = Vec::new();
+    let _ = IoServer::new(input, &mut output).serve().await;
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("init_ok"));
+    assert_eq!(1, output.lines().count());
+}
+
+#[tokio::test(start_paused = true)]
+async fn rpc_times_out_with_code_0() {
+    let policy = RpcPolicy::default().timeout(Duration::from_millis(100));
+    let output = start_with_replies(vec![], Some(policy)).await;
+    assert_eq!(
+        parse_json(START_TIMEOUT_RESPONSE),
+        process_output(output, START_TIMEOUT_RESPONSE)
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn rpc_times_out_by_default() {
+    let output = start_with_replies(vec![], None).await;
+    assert_eq!(
+        parse_json(START_TIMEOUT_RESPONSE),
+        process_output(output, START_TIMEOUT_RESPONSE)
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn rpc_that_timed_out_is_forgotten() {
+    let input = scripted_input(
+        vec![
+            (Duration::ZERO, init::REQUEST),
+            (Duration::ZERO, START_REQUEST),
+        ],
+        Duration::from_secs(1),
+    );
+    let output = serve_with_start(
+        input,
+        |_| {},
+        |context: SharedIoServerContext, req| async move {
+            let policy = RpcPolicy::default().timeout(Duration::from_millis(100));
+            let ping = context
+                .rpc_with("n2", json!({"type": "ping"}), &policy)
+                .await;
+            assert!(ping.is_err());
+            let pending = context.call(|ctx| ctx.pending_rpcs().len()).await?;
+            reply_to(
+                &context,
+                &req,
+                json!({"type": "start_ok", "pending": pending}),
+            )
+            .await
+        },
+    )
+    .await;
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains(r#""pending":0"#), "{output}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn rpc_retries_indefinite_errors() {
+    // The retry is sent with the next msg_id
+    let retried_reply = PONG_REPLY.replace(r#""in_reply_to": 2"#, r#""in_reply_to": 3"#);
+    let policy = RpcPolicy::default()
+        .max_attempts(2)
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .jitter(false);
+    let replies = vec![
+        (Duration::from_millis(100), CRASH_REPLY),
+        (Duration::from_millis(100), retried_reply.as_str()),
+    ];
+    let output = start_with_replies(replies, Some(policy)).await;
+    let output_text = String::from_utf8(output.clone()).unwrap();
+    assert_eq!(2, output_text.matches(r#""type":"ping""#).count());
+    let expected = START_OK_RESPONSE.replace(r#""msg_id": 3"#, r#""msg_id": 4"#);
+    assert_eq!(parse_json(&expected), process_output(output, &expected));
+}
+
+#[tokio::test(start_paused = true)]
+async fn rpc_does_not_retry_definite_errors() {
+    let policy = RpcPolicy::default().max_attempts(3);
+    let replies = vec![(Duration::from_millis(100), KEY_ERROR_REPLY)];
+    let output = start_with_replies(replies, Some(policy)).await;
+    let output_text = String::from_utf8(output.clone()).unwrap();
+    assert_eq!(1, output_text.matches(r#""type":"ping""#).count());
+    assert_eq!(
+        parse_json(START_ERROR_RESPONSE),
+        process_output(output, START_ERROR_RESPONSE)
+    );
+}
+
+#[test_case(None ; "default limit")]
+#[test_case(Some(1) ; "one request at a time")]
+#[tokio::test(start_paused = true)]
+async fn pipelined_requests_get_their_rpc_replies(limit: Option<usize>) {
+    // With a limit of 1, the second `start` waits for the first one, whose ping is answered
+    // while it's waiting, so its ping is sent after the first `start_ok`
+    let second_start = START_REQUEST
+        .replace(r#""src": "c1""#, r#""src": "c2""#)
+        .replace(r#""msg_id": 1"#, r#""msg_id": 2"#);
+    let second_ping = if limit.is_some() { 4 } else { 3 };
+    let second_reply = PONG_REPLY.replace(
+        r#""in_reply_to": 2"#,
+        &format!(r#""in_reply_to": {second_ping}"#),
+    );
+    let replies = vec![
+        (Duration::from_millis(100), PONG_REPLY),
+        (Duration::from_millis(100), second_reply.as_str()),
+    ];
+    let mut input = vec![
+        (Duration::ZERO, init::REQUEST),
+        (Duration::ZERO, START_REQUEST),
+        (Duration::ZERO, &second_start),
+    ];
+    input.extend(replies);
+    let output = serve_starts(input, None, |server| {
+        if let Some(limit) = limit {
+            server.concurrency_limit(limit);
+        }
+    })
+    .await;
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(
+        2,
+        output.matches(r#""type":"start_ok""#).count(),
+        "{output}"
+    );
+    assert!(!output.contains(r#""type":"error""#), "{output}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn request_buffered_until_init_gets_its_rpc_reply() {
+    // The ping is only answered once the node reads on after `init`
+    let input = vec![
+        (Duration::ZERO, START_REQUEST),
+        (Duration::ZERO, init::REQUEST),
+        (Duration::from_millis(100), PONG_REPLY),
+    ];
+    let output = serve_starts(input, None, |server| {
+        server.buffer_until_init(true);
+    })
+    .await;
+    assert_eq!(
+        parse_json(START_OK_RESPONSE),
+        process_output(output, START_OK_RESPONSE)
+    );
+}
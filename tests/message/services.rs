@@ -0,0 +1,206 @@
+use crate::helper::reply_to;
+use maelstrom_lib::{
+    message::{handler_fn, RawRequest},
+    server::{
+        stdio::{IoServer, SharedIoServerContext},
+        transport::{
+            memory::{MemoryNetwork, MemoryTransport},
+            Transport, TransportInput, TransportOutput,
+        },
+    },
+    services::{kv::KvClient, local::LocalService},
+};
+use serde_json::{json, Value};
+use std::{io::Write, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, Lines},
+    time::timeout,
+};
+
+/// A client sending requests to the services of a [`MemoryNetwork`].
+pub struct Client {
+    id: &'static str,
+    replies: Lines<TransportInput>,
+    output: TransportOutput,
+    msg_id: u64,
+}
+
+impl Client {
+    pub fn new(network: &MemoryNetwork, id: &'static str) -> Self {
+        let (input, output) = network.join(id).open().unwrap();
+        Self {
+            id,
+            replies: input.lines(),
+            output,
+            msg_id: 0,
+        }
+    }
+
+    pub async fn request(&mut self, dest: &str, body: Value) -> Value {
+        let msg_id = self.send(dest, body);
+        let mut body = self.receive().await;
+        assert_eq!(json!(msg_id), body["in_reply_to"]);
+        body.as_object_mut().unwrap().remove("in_reply_to");
+        body
+    }
+
+    /// Sends a request without waiting for its reply, returning its `msg_id`.
+    pub fn send(&mut self, dest: &str, mut body: Value) -> u64 {
+        self.msg_id += 1;
+        body["msg_id"] = json!(self.msg_id);
+        let request = json!({"src": self.id, "dest": dest, "body": body});
+        writeln!(self.output, "{request}").unwrap();
+        self.output.flush().unwrap();
+        self.msg_id
+    }
+
+    /// Waits for the body of the next reply.
+    pub async fn receive(&mut self) -> Value {
+        let reply = timeout(Duration::from_secs(5), self.replies.next_line())
+            .await
+            .expect("the reply should arrive")
+            .unwrap()
+            .unwrap();
+        serde_json::from_str::<Value>(&reply).unwrap()["body"].clone()
+    }
+}
+
+/// Starts `service` on `network` as `id`.
+pub fn start_service(network: &MemoryNetwork, id: &str, service: LocalService) {
+    let transport: MemoryTransport = network.join(id);
+    tokio::spawn(service.serve_transport(transport));
+}
+
+async fn read(client: &mut Client, key: &str) -> Value {
+    client
+        .request("kv", json!({"type": "read", "key": key}))
+        .await
+}
+
+async fn write(client: &mut Client, key: &str, value: u64) -> Value {
+    let write = json!({"type": "write", "key": key, "value": value});
+    client.request("kv", write).await
+}
+
+#[tokio::test]
+async fn lin_kv_serves_reads_writes_and_cas() {
+    let network = MemoryNetwork::new();
+    start_service(&network, "kv", LocalService::lin_kv());
+    let mut client = Client::new(&network, "c1");
+
+    let missing = read(&mut client, "x").await;
+    assert_eq!(json!("error"), missing["type"]);
+    assert_eq!(json!(20), missing["code"]);
+    assert_eq!(
+        json!({"type": "write_ok"}),
+        write(&mut client, "x", 1).await
+    );
+    assert_eq!(
+        json!({"type": "read_ok", "value": 1}),
+        read(&mut client, "x").await
+    );
+
+    let cas = json!({"type": "cas", "key": "x", "from": 2, "to": 3});
+    assert_eq!(json!(22), client.request("kv", cas).await["code"]);
+    let cas = json!({"type": "cas", "key": "x", "from": 1, "to": 3});
+    assert_eq!(json!({"type": "cas_ok"}), client.request("kv", cas).await);
+    let cas = json!({"type": "cas", "key": "y", "from": 0, "to": 1});
+    assert_eq!(json!(20), client.request("kv", cas).await["code"]);
+    let cas = json!({"type": "cas", "key": "y", "from": 0, "to": 1, "create_if_not_exists": true});
+    assert_eq!(json!({"type": "cas_ok"}), client.request("kv", cas).await);
+    assert_eq!(json!(1), read(&mut client, "y").await["value"]);
+}
+
+#[tokio::test]
+async fn seq_kv_serves_stale_reads_that_never_go_back() {
+    let network = MemoryNetwork::new();
+    start_service(&network, "kv", LocalService::seq_kv(1));
+    let (mut c1, mut c2) = (Client::new(&network, "c1"), Client::new(&network, "c2"));
+
+    write(&mut c1, "x", 1).await;
+    write(&mut c1, "x", 2).await;
+    // c2 may see the store as it was a write ago, while c1 sees its own writes
+    assert_eq!(json!(1), read(&mut c2, "x").await["value"]);
+    assert_eq!(json!(2), read(&mut c1, "x").await["value"]);
+
+    // Once c2 wrote, it can't see an older store anymore
+    write(&mut c2, "y", 1).await;
+    assert_eq!(json!(2), read(&mut c2, "x").await["value"]);
+    // ...while c1 may not see c2's write yet
+    assert_eq!(json!(20), read(&mut c1, "y").await["code"]);
+    write(&mut c1, "z", 1).await;
+    assert_eq!(json!(1), read(&mut c1, "y").await["value"]);
+
+    // A failed cas saw the latest value, so the next reads can't be older
+    write(&mut c1, "x", 3).await;
+    let cas = json!({"type": "cas", "key": "x", "from": 2, "to": 4});
+    assert_eq!(json!(22), c2.request("kv", cas).await["code"]);
+    assert_eq!(json!(3), read(&mut c2, "x").await["value"]);
+}
+
+#[tokio::test]
+async fn lww_kv_converges_on_the_last_write() {
+    let network = MemoryNetwork::new();
+    start_service(&network, "kv", LocalService::lww_kv(2, 1));
+    let (mut c1, mut c2) = (Client::new(&network, "c1"), Client::new(&network, "c2"));
+
+    write(&mut c1, "x", 1).await;
+    write(&mut c2, "x", 2).await;
+    // Each client sees its own write on its replica
+    assert_eq!(json!(2), read(&mut c2, "x").await["value"]);
+    for _ in 0..2 {
+        read(&mut c1, "x").await;
+    }
+    assert_eq!(json!(2), read(&mut c1, "x").await["value"]);
+    assert_eq!(json!(2), read(&mut c2, "x").await["value"]);
+}
+
+#[tokio::test]
+async fn lin_tso_hands_out_increasing_timestamps() {
+    let network = MemoryNetwork::new();
+    start_service(&network, "tso", LocalService::lin_tso());
+    let mut client = Client::new(&network, "c1");
+    for ts in 0..3 {
+        assert_eq!(
+            json!({"type": "ts_ok", "ts": ts}),
+            client.request("tso", json!({"type": "ts"})).await
+        );
+    }
+    let read = json!({"type": "read", "key": "x"});
+    assert_eq!(json!(10), client.request("tso", read).await["code"]);
+}
+
+#[tokio::test]
+async fn nodes_use_local_services_like_maelstrom_ones() {
+    let network = MemoryNetwork::new();
+    start_service(&network, "lin-kv", LocalService::lin_kv());
+    let mut node = IoServer::with_transport(network.join("n1")).unwrap();
+    node.register(
+        "add",
+        handler_fn(
+            |context: SharedIoServerContext, req: RawRequest| async move {
+                let kv = KvClient::lin_kv(context);
+                kv.cas(&"sum", &0, &0, true).await?;
+                let sum = kv.read::<_, u64>(&"sum").await?;
+                kv.cas(&"sum", &sum, &(sum + 5), false).await?;
+                reply_to(
+                    kv.context(),
+                    &req,
+                    json!({"type": "add_ok", "sum": sum + 5}),
+                )
+                .await
+            },
+        ),
+    );
+    let shutdown = node.shutdown_handle();
+    tokio::spawn(async move { node.serve().await });
+
+    let mut client = Client::new(&network, "c1");
+    let init = json!({"type": "init", "node_id": "n1", "node_ids": ["n1"]});
+    assert_eq!(json!("init_ok"), client.request("n1", init).await["type"]);
+    assert_eq!(
+        json!(5),
+        client.request("n1", json!({"type": "add"})).await["sum"]
+    );
+    shutdown.shutdown();
+}
@@ -1,11 +1,33 @@
-use crate::helper::{can_serde, parse_json};
+use crate::{
+    helper::{
+        can_serde, delayed_eof_input, parse_json, process_output, reply_to, serde_vec_to_string,
+    },
+    init,
+};
+use assert_matches::assert_matches;
+use futures::FutureExt;
 use maelstrom_lib::{
-    error::MaelstromError::{self, SerdeJsonError},
-    message::{Body, Message, RequestTypes::Echo, WorkloadHandler},
-    server::stdio::{send_message, IoServer, SharedIoServerContext},
+    error::MaelstromError::SerdeJsonError,
+    message::{handler_fn, Body, HandlerFuture, Message, RawRequest, RequestType, WorkloadHandler},
+    server::{
+        input::SyncBufReader,
+        outbound::OutputBatching,
+        schedule::PeriodicTask,
+        stdio::{send_message, IoServer, IoServerContext, SharedIoServerContext},
+    },
 };
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::{
+    io::{Cursor, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use test_case::test_case;
+use tokio::io::{AsyncWriteExt, BufReader};
 
 #[derive(Deserialize, Serialize, Clone, Debug, Eq, PartialEq)]
 pub struct Request(Message<Value>);
@@ -16,23 +38,26 @@ pub struct Response(Message<Value>);
 pub struct MockEchoHandler {}
 
 impl WorkloadHandler for MockEchoHandler {
-    fn response(_context: SharedIoServerContext, req: Value) -> Result<String, MaelstromError> {
-        let req: Request = serde_json::from_value(req)?;
-        let Request(Message { src, dest, body }) = req;
-
-        let response = Response(Message {
-            src: dest,
-            dest: src,
-            body: Body {
-                msg_id: None,
-                in_reply_to: None,
-                content: json!({
-                    "type": "echo_ok",
-                    "msg": body.content.get("msg").unwrap()
-                }),
-            },
-        });
-        serde_json::to_string(&response).map_err(SerdeJsonError)
+    fn response(&self, _context: SharedIoServerContext, req: RawRequest) -> HandlerFuture {
+        async move {
+            let req: Request = req.deserialize()?;
+            let Request(Message { src, dest, body }) = req;
+
+            let response = Response(Message {
+                src: dest,
+                dest: src,
+                body: Body {
+                    msg_id: None,
+                    in_reply_to: None,
+                    content: json!({
+                        "type": "echo_ok",
+                        "msg": body.content.get("msg").unwrap()
+                    }),
+                },
+            });
+            serde_json::to_string(&response).map_err(SerdeJsonError)
+        }
+        .boxed()
     }
 }
 
@@ -60,18 +85,268 @@ const RESPONSE: &str = r#"
 
 #[tokio::test]
 async fn works_with_registered_service2() {
-    let input = &parse_json(REQUEST);
+    let input = &serde_vec_to_string(vec![init::REQUEST, REQUEST]);
     let expected_output = &parse_json(RESPONSE);
     let mut output = Vec::new();
     let _ = IoServer::new(input.as_bytes(), &mut output)
-        .register(Echo, MockEchoHandler::response)
+        .register(RequestType::ECHO, MockEchoHandler {})
         .serve()
         .await;
-    let output = parse_json(&String::from_utf8(output).unwrap());
+    let output = process_output(output, RESPONSE);
     dbg!(&input, &output, RESPONSE, expected_output);
     assert_eq!(expected_output, &output);
 }
 
+#[tokio::test]
+async fn works_with_sync_reader() {
+    let input = serde_vec_to_string(vec![init::REQUEST, REQUEST]);
+    let input = SyncBufReader::new(Cursor::new(input));
+    let expected_output = &parse_json(RESPONSE);
+    let mut output = Vec::new();
+    let _ = IoServer::new(input, &mut output)
+        .register(RequestType::ECHO, MockEchoHandler {})
+        .serve()
+        .await;
+    let output = process_output(output, RESPONSE);
+    assert_eq!(expected_output, &output);
+}
+
+#[tokio::test]
+async fn serve_returns_after_shutdown() {
+    let (mut writer, reader) = tokio::io::duplex(1024);
+    writer
+        .write_all(format!("{}\n", serde_vec_to_string(vec![init::REQUEST, REQUEST])).as_bytes())
+        .await
+        .unwrap();
+    let mut output = Vec::new();
+    let mut server = IoServer::new(BufReader::new(reader), &mut output);
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        shutdown.shutdown();
+    });
+    // The input is never closed, so only the shutdown handle can stop the server
+    let result = tokio::time::timeout(
+        Duration::from_secs(5),
+        server
+            .register(RequestType::ECHO, MockEchoHandler {})
+            .serve(),
+    )
+    .await;
+    assert_matches!(result, Ok(Ok(())));
+    let output = process_output(output, RESPONSE);
+    assert_eq!(&parse_json(RESPONSE), &output);
+    drop(writer);
+}
+
+#[tokio::test]
+async fn works_with_stateful_async_handler() {
+    let input = &serde_vec_to_string(vec![init::REQUEST, REQUEST, REQUEST]);
+    let calls = Arc::new(AtomicUsize::new(0));
+    let handler_calls = calls.clone();
+    let mut output = Vec::new();
+    let _ = IoServer::new(input.as_bytes(), &mut output)
+        .register(
+            RequestType::ECHO,
+            handler_fn(move |context, req| {
+                let calls = handler_calls.clone();
+                async move {
+                    tokio::task::yield_now().await;
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    MockEchoHandler {}.response(context, req).await
+                }
+            }),
+        )
+        .serve()
+        .await;
+    let output = String::from_utf8(output).unwrap();
+    dbg!(&input, &output);
+    assert_eq!(2, calls.load(Ordering::SeqCst));
+    assert_eq!(2, output.matches("echo_ok").count());
+}
+
+#[tokio::test]
+async fn handlers_keep_state_in_workload_slots() {
+    #[derive(Default)]
+    struct EchoCount(usize);
+
+    let input = &serde_vec_to_string(vec![init::REQUEST, REQUEST, REQUEST]);
+    let mut output = Vec::new();
+    let _ = IoServer::new(input.as_bytes(), &mut output)
+        .register(
+            RequestType::ECHO,
+            handler_fn(
+                |context: SharedIoServerContext, req: RawRequest| async move {
+                    let count = context
+                        .call(|ctx| {
+                            let count = ctx.workload_state_mut::<EchoCount>();
+                            count.0 += 1;
+                            count.0
+                        })
+                        .await?;
+                    let mut req = req.deserialize::<Value>()?;
+                    req["body"]["msg"] = json!(count.to_string());
+                    MockEchoHandler {}
+                        .response(context, RawRequest::parse(req.to_string())?)
+                        .await
+                },
+            ),
+        )
+        .serve()
+        .await;
+    let output = String::from_utf8(output).unwrap();
+    dbg!(&input, &output);
+    assert!(output.contains(r#""msg":"1""#));
+    assert!(output.contains(r#""msg":"2""#));
+}
+
+#[tokio::test]
+async fn runs_scheduled_periodic_tasks() {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let task_runs = runs.clone();
+    let task = PeriodicTask::new(Duration::from_millis(20), move |_context| {
+        let runs = task_runs.clone();
+        async move {
+            runs.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    })
+    .jitter(Duration::from_millis(5))
+    .run_on_shutdown(true);
+
+    let input = delayed_eof_input(init::REQUEST, Duration::from_millis(200));
+    let mut output = Vec::new();
+    let _ = IoServer::new(input, &mut output)
+        .schedule(task)
+        .serve()
+        .await;
+    let runs = runs.load(Ordering::SeqCst);
+    dbg!(runs);
+    assert!((2..=11).contains(&runs));
+}
+
+#[tokio::test]
+async fn flushes_messages_sent_through_the_node_on_shutdown() {
+    let task = PeriodicTask::new(
+        Duration::from_secs(60),
+        |context: SharedIoServerContext| async move {
+            // Queue enough commands that the node's task can't apply them all before yielding
+            for _ in 0..1000 {
+                context.cast(|_| {})?;
+            }
+            context.cast(|ctx| {
+                let _ = ctx
+                    .outbound()
+                    .send(r#"{"src":"n1","dest":"n2","body":{"type":"bye"}}"#.into());
+            })
+        },
+    )
+    .run_on_shutdown(true);
+
+    let input = delayed_eof_input(init::REQUEST, Duration::from_millis(50));
+    let mut output = Vec::new();
+    let _ = IoServer::new(input, &mut output)
+        .schedule(task)
+        .serve()
+        .await;
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains(r#""type":"bye""#), "{output}");
+}
+
+#[test_case(Some(1), &["slow", "fast"] ; "sequentially with a limit of 1")]
+#[test_case(Some(2), &["fast", "slow"] ; "concurrently when allowed")]
+#[test_case(None, &["fast", "slow"] ; "concurrently by default")]
+#[tokio::test]
+async fn processes_requests_with_concurrency_limit(limit: Option<usize>, expected: &[&str]) {
+    let request = |msg: &str| REQUEST.replace("The answer is 42", msg);
+    let input = &serde_vec_to_string(vec![init::REQUEST, &request("slow"), &request("fast")]);
+    let mut output = Vec::new();
+    let mut server = IoServer::new(input.as_bytes(), &mut output);
+    if let Some(limit) = limit {
+        server.concurrency_limit(limit);
+    }
+    let _ = server
+        .register(
+            RequestType::ECHO,
+            handler_fn(|context, req: RawRequest| async move {
+                if req.deserialize::<Value>()?["body"]["msg"] == "slow" {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                }
+                MockEchoHandler {}.response(context, req).await
+            }),
+        )
+        .serve()
+        .await;
+    let output = String::from_utf8(output).unwrap();
+    dbg!(&input, &output);
+    let replies = output
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap()["body"].clone())
+        .filter(|body| body["type"] == "echo_ok")
+        .map(|body| body["msg"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(expected, replies);
+}
+
+#[tokio::test]
+async fn rejects_requests_that_do_not_fit_in_the_queue() {
+    let request = |msg: &str| REQUEST.replace("The answer is 42", msg);
+    let input = &serde_vec_to_string(vec![init::REQUEST, &request("slow"), &request("fast")]);
+    let mut output = Vec::new();
+    let _ = IoServer::new(input.as_bytes(), &mut output)
+        .concurrency_limit(1)
+        .queue_limit(0)
+        .register(
+            RequestType::ECHO,
+            handler_fn(|context, req: RawRequest| async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                MockEchoHandler {}.response(context, req).await
+            }),
+        )
+        .serve()
+        .await;
+    let output = String::from_utf8(output).unwrap();
+    let replies = output
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap()["body"].clone())
+        .filter(|body| body["type"] != "init_ok")
+        .map(|body| (body["type"].clone(), body["code"].clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        vec![(json!("error"), json!(11)), (json!("echo_ok"), Value::Null)],
+        replies
+    );
+}
+
+#[tokio::test]
+async fn works_with_custom_request_type() {
+    #[derive(Deserialize, Serialize)]
+    struct Ping {
+        seq: u64,
+    }
+
+    let input = &serde_vec_to_string(vec![
+        init::REQUEST,
+        r#"{"src": "c1", "dest": "n1", "body": {"type": "ping", "msg_id": 7, "seq": 3}}"#,
+    ]);
+    let mut output = Vec::new();
+    let _ = IoServer::new(input.as_bytes(), &mut output)
+        .register(
+            "ping",
+            handler_fn(
+                |context: SharedIoServerContext, req: RawRequest| async move {
+                    let ping: Message<Ping> = req.deserialize()?;
+                    let pong = json!({"type": "pong", "seq": ping.body.content.seq});
+                    reply_to(&context, &req, pong).await
+                },
+            ),
+        )
+        .serve()
+        .await;
+    let expected = r#"{"src": "n1", "dest": "c1", "body": {"type": "pong", "msg_id": 2, "in_reply_to": 7, "seq": 3}}"#;
+    assert_eq!(parse_json(expected), process_output(output, expected));
+}
+
 #[tokio::test]
 async fn test_serde() {
     can_serde::<Request>(REQUEST);
@@ -85,7 +360,65 @@ async fn fails_if_not_registered_with_service() {
     let _ = IoServer::new(input.as_bytes(), &mut output).serve().await;
     let output = String::from_utf8(output).unwrap();
     dbg!(&input, &output);
-    assert!(output.contains("No handler found for request type"));
+    assert!(output.contains("echo is not supported"));
+}
+
+/// Output that counts how many times it was flushed.
+#[derive(Default)]
+struct FlushCounter {
+    data: Vec<u8>,
+    flushes: usize,
+}
+
+impl Write for FlushCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Write::write(&mut self.data, buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.flushes += 1;
+        Ok(())
+    }
+}
+
+#[test_case(OutputBatching::default(), 2 ; "batched until the client reply")]
+#[test_case(OutputBatching::disabled(), 27 ; "one by one when disabled")]
+#[tokio::test]
+async fn coalesces_outbound_writes(batching: OutputBatching, expected_flushes: usize) {
+    let start = r#"{"src": "c1", "dest": "n1", "body": {"type": "start", "msg_id": 2}}"#;
+    let input = serde_vec_to_string(vec![init::REQUEST, start]);
+    let input = delayed_eof_input(&input, Duration::from_millis(50));
+    let mut output = FlushCounter::default();
+    let _ = IoServer::new(input, &mut output)
+        .output_batching(batching)
+        .register(
+            "start",
+            handler_fn(|context: SharedIoServerContext, req: RawRequest| async move {
+                let outbound = context.outbound().clone();
+                for node in 2..=26 {
+                    let gossip = json!({"src": "n1", "dest": format!("n{node}"), "body": {"type": "gossip"}});
+                    outbound.send(gossip.to_string())?;
+                }
+                reply_to(&context, &req, json!({"type": "start_ok"})).await
+            }),
+        )
+        .serve()
+        .await;
+    let lines = String::from_utf8(output.data).unwrap();
+    dbg!(&lines);
+    assert_eq!(27, lines.lines().count());
+    assert!(lines.lines().last().unwrap().contains("start_ok"));
+    assert_eq!(expected_flushes, output.flushes);
+}
+
+#[test]
+fn clients_are_the_destinations_outside_the_cluster() {
+    let mut context = IoServerContext::default();
+    assert!(context.outbound().is_client("n2"));
+    let nodes = vec!["n1".to_string(), "c2".to_string()];
+    context.initialize("n1".into(), &nodes).unwrap();
+    assert!(!context.outbound().is_client("c2"));
+    assert!(context.outbound().is_client("x7"));
 }
 
 #[tokio::test]
@@ -97,3 +430,23 @@ async fn test_send_message() {
         .expect("send_message should return data");
     assert_eq!(expected, actual);
 }
+
+#[test]
+fn raw_request_peeks_envelope() {
+    let req = RawRequest::parse(
+        r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":7,"msg":{"nested":[1,2]}}}"#
+            .to_string(),
+    )
+    .unwrap();
+    assert_eq!(("c1", "n1"), (req.src(), req.dest()));
+    assert_eq!(Some(&RequestType::ECHO), req.msg_type());
+    assert_eq!((Some(7), None), (req.msg_id(), req.in_reply_to()));
+    let req: Request = req.deserialize().unwrap();
+    assert_eq!(json!({"nested": [1, 2]}), req.0.body.content["msg"]);
+
+    let reply: RawRequest = r#"{"src":"n2","dest":"n1","body":{"in_reply_to":3}}"#
+        .parse()
+        .unwrap();
+    assert_eq!((None, Some(3)), (reply.msg_type(), reply.in_reply_to()));
+    assert!(RawRequest::parse("not json".to_string()).is_err());
+}
@@ -0,0 +1,112 @@
+use crate::{
+    helper::{delayed_eof_input, serde_vec_to_string, serve_with_start, START_REQUEST},
+    init,
+};
+use maelstrom_lib::{
+    message::{handler_fn, RawRequest},
+    server::{
+        stdio::{IoServerContext, SharedIoServerContext},
+        timer::TimerHandle,
+    },
+};
+use serde_json::json;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+#[derive(Default)]
+struct Ticker(Option<TimerHandle>);
+
+/// Serves `init` and `start`, counting the `tick` events delivered to the node.
+async fn count_ticks<F>(start: F) -> usize
+where
+    F: Fn(&mut IoServerContext) + Send + Sync + 'static,
+{
+    let start = Arc::new(start);
+    let ticks = Arc::new(AtomicUsize::new(0));
+    let handler_ticks = ticks.clone();
+    let input = serde_vec_to_string(vec![init::REQUEST, START_REQUEST]);
+    let input = delayed_eof_input(&input, Duration::from_secs(1));
+    let tick = handler_fn(move |context: SharedIoServerContext, _req: RawRequest| {
+        let ticks = handler_ticks.clone();
+        async move {
+            if ticks.fetch_add(1, Ordering::SeqCst) + 1 == 3 {
+                context
+                    .call(|ctx| {
+                        if let Some(Ticker(Some(timer))) = ctx.workload_state::<Ticker>() {
+                            timer.cancel();
+                        }
+                    })
+                    .await?;
+            }
+            Ok(String::new())
+        }
+    });
+    serve_with_start(
+        input,
+        |server| {
+            server.register("tick", tick);
+        },
+        move |context: SharedIoServerContext, _req| {
+            let start = start.clone();
+            async move {
+                context.call(move |ctx| start(ctx)).await?;
+                Ok(String::new())
+            }
+        },
+    )
+    .await;
+    ticks.load(Ordering::SeqCst)
+}
+
+#[tokio::test(start_paused = true)]
+async fn one_shot_timer_is_delivered_once() {
+    let ticks = count_ticks(|ctx| {
+        let _ = ctx.schedule_after(Duration::from_millis(200), json!({"type": "tick"}));
+    })
+    .await;
+    assert_eq!(1, ticks);
+}
+
+#[tokio::test(start_paused = true)]
+async fn recurring_timer_runs_until_cancelled() {
+    let ticks = count_ticks(|ctx| {
+        let timer = ctx.every(Duration::from_millis(100), json!({"type": "tick"}));
+        ctx.set_workload_state(Ticker(timer.ok()));
+    })
+    .await;
+    assert_eq!(3, ticks);
+}
+
+#[tokio::test(start_paused = true)]
+async fn cancelled_timer_is_not_delivered() {
+    let ticks = count_ticks(|ctx| {
+        if let Ok(timer) = ctx.schedule_after(Duration::from_millis(200), json!({"type": "tick"})) {
+            timer.cancel();
+        }
+    })
+    .await;
+    assert_eq!(0, ticks);
+}
+
+#[tokio::test(start_paused = true)]
+async fn recurring_timer_stops_with_the_server() {
+    let timer = Arc::new(Mutex::new(None));
+    let started = timer.clone();
+    let ticks = count_ticks(move |ctx| {
+        let handle = ctx.every(Duration::from_millis(100), json!({"type": "tick"}));
+        *started.lock().unwrap() = handle.ok();
+    })
+    .await;
+    assert!(ticks > 3);
+    let timer = timer
+        .lock()
+        .unwrap()
+        .take()
+        .expect("the timer should be started");
+    assert!(timer.is_cancelled());
+}
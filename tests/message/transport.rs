@@ -0,0 +1,151 @@
+use maelstrom_lib::server::{
+    shutdown::ShutdownHandle,
+    stdio::{IoServer, IoServerType},
+    transport::{memory::MemoryNetwork, tcp::TcpTransport, Transport},
+};
+use serde_json::{json, Value};
+use std::{io::Write, time::Duration};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, Lines},
+    time::{sleep, timeout},
+};
+
+/// A client of the cluster, talking to it over its own transport.
+struct Client<I, O> {
+    replies: Lines<I>,
+    output: O,
+    msg_id: u64,
+}
+
+impl<I: AsyncBufRead + Unpin, O: Write> Client<I, O> {
+    fn new<T: Transport<Input = I, Output = O>>(transport: T) -> Self {
+        let (input, output) = transport.open().unwrap();
+        Self {
+            replies: input.lines(),
+            output,
+            msg_id: 0,
+        }
+    }
+
+    async fn request(&mut self, dest: &str, mut body: Value) -> Value {
+        self.msg_id += 1;
+        body["msg_id"] = json!(self.msg_id);
+        let request = json!({"src": "c1", "dest": dest, "body": body});
+        writeln!(self.output, "{request}").unwrap();
+        self.output.flush().unwrap();
+        let reply = timeout(Duration::from_secs(5), self.replies.next_line())
+            .await
+            .expect("the reply should arrive")
+            .unwrap()
+            .unwrap();
+        serde_json::from_str::<Value>(&reply).unwrap()["body"].clone()
+    }
+}
+
+/// Starts a broadcast node over `transport`.
+fn start_node<T>(transport: T) -> ShutdownHandle
+where
+    T: Transport,
+    T::Input: Send + 'static,
+    T::Output: Send + 'static,
+{
+    let mut server = IoServer::with_transport(transport).unwrap();
+    server.workload(IoServerType::Broadcast);
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(async move { server.serve().await });
+    shutdown
+}
+
+/// Broadcasts a message to n1, and waits for n1 to gossip it to n2.
+async fn gossips_between_nodes<I, O>(nodes: Vec<ShutdownHandle>, mut client: Client<I, O>)
+where
+    I: AsyncBufRead + Unpin,
+    O: Write,
+{
+    for node in ["n1", "n2"] {
+        let init = json!({"type": "init", "node_id": node, "node_ids": ["n1", "n2"]});
+        assert_eq!("init_ok", client.request(node, init).await["type"]);
+        let topology = json!({"type": "topology", "topology": {"n1": ["n2"], "n2": ["n1"]}});
+        assert_eq!("topology_ok", client.request(node, topology).await["type"]);
+    }
+    let broadcast = json!({"type": "broadcast", "message": 7});
+    assert_eq!(
+        "broadcast_ok",
+        client.request("n1", broadcast).await["type"]
+    );
+
+    let mut messages = Value::Null;
+    for _ in 0..40 {
+        messages = client.request("n2", json!({"type": "read"})).await["messages"].clone();
+        if messages == json!([7]) {
+            break;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(json!([7]), messages);
+    for node in nodes {
+        node.shutdown();
+    }
+}
+
+#[tokio::test]
+async fn memory_network_runs_a_cluster() {
+    let network = MemoryNetwork::new();
+    let nodes = vec![
+        start_node(network.join("n1")),
+        start_node(network.join("n2")),
+    ];
+    gossips_between_nodes(nodes, Client::new(network.join("c1"))).await;
+}
+
+#[tokio::test]
+async fn tcp_transport_runs_a_cluster() {
+    let n1 = TcpTransport::bind("127.0.0.1:0").unwrap();
+    let n2 = TcpTransport::bind("127.0.0.1:0").unwrap();
+    let client = TcpTransport::bind("127.0.0.1:0").unwrap();
+    let (n1_addr, n2_addr) = (n1.local_addr().unwrap(), n2.local_addr().unwrap());
+    let nodes = vec![
+        start_node(n1.peer("n2", n2_addr)),
+        start_node(n2.peer("n1", n1_addr)),
+    ];
+    // The nodes reply to the client over the connections it opened
+    let client = client.peer("n1", n1_addr).peer("n2", n2_addr);
+    gossips_between_nodes(nodes, Client::new(client)).await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_transport_runs_a_cluster() {
+    use maelstrom_lib::server::transport::unix::UnixTransport;
+
+    let dir = std::env::temp_dir().join(format!("maelstrom-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let nodes = vec![
+        start_node(UnixTransport::new(&dir, "n1")),
+        start_node(UnixTransport::new(&dir, "n2")),
+    ];
+    gossips_between_nodes(nodes, Client::new(UnixTransport::new(&dir, "c1"))).await;
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_transport_only_connects_within_its_directory() {
+    use maelstrom_lib::server::transport::unix::UnixTransport;
+    use tokio::net::UnixListener;
+
+    let base = std::env::temp_dir().join(format!("maelstrom-{}", uuid::Uuid::new_v4()));
+    let dir = base.join("cluster");
+    std::fs::create_dir_all(&dir).unwrap();
+    let outside = UnixListener::bind(base.join("outside.sock")).unwrap();
+    let (_input, mut output) = UnixTransport::new(&dir, "n1").open().unwrap();
+    let absolute = base.join("outside");
+    for dest in ["../outside", absolute.to_str().unwrap()] {
+        let message = json!({"src": "n1", "dest": dest, "body": {"type": "echo"}});
+        writeln!(output, "{message}").unwrap();
+    }
+    assert!(timeout(Duration::from_millis(200), outside.accept())
+        .await
+        .is_err());
+    let _ = std::fs::remove_dir_all(base);
+}
@@ -0,0 +1,91 @@
+use crate::{
+    helper::{reply_to, scripted_input, serve_with_start, START_REQUEST},
+    init,
+};
+use maelstrom_lib::services::tso::TsoClient;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Serves a `start` request that gets 3 timestamps, prefetching `prefetch` at a time, while
+/// lin-tso answers each `ts` request (from msg_id 2, after `init_ok`) with `timestamps`.
+/// Returns the number of `ts` requests and the `start` reply.
+async fn start_with_timestamps(prefetch: usize, timestamps: &[u64]) -> (usize, Value) {
+    let replies = timestamps
+        .iter()
+        .zip(2..)
+        .map(|(ts, msg_id)| {
+            json!({
+                "src": "lin-tso",
+                "dest": "n1",
+                "body": {"type": "ts_ok", "in_reply_to": msg_id, "ts": ts}
+            })
+            .to_string()
+        })
+        .collect::<Vec<_>>();
+    let mut input = vec![
+        (Duration::ZERO, init::REQUEST),
+        (Duration::ZERO, START_REQUEST),
+    ];
+    input.extend(
+        replies
+            .iter()
+            .map(|reply| (Duration::from_millis(100), reply.as_str())),
+    );
+    let input = scripted_input(input, Duration::from_secs(1));
+    let output = serve_with_start(
+        input,
+        |_| {},
+        move |context, req| async move {
+            let tso = TsoClient::lin_tso(context.clone()).prefetch(prefetch);
+            let mut timestamps = Vec::new();
+            for _ in 0..3 {
+                timestamps.push(tso.timestamp().await?);
+            }
+            let body = json!({"type": "start_ok", "timestamps": timestamps});
+            reply_to(&context, &req, body).await
+        },
+    )
+    .await;
+    let output = String::from_utf8(output).unwrap();
+    let messages: Vec<Value> = output
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let requests = messages
+        .iter()
+        .filter(|message| message["dest"] == "lin-tso")
+        .inspect(|message| assert_eq!("ts", message["body"]["type"]))
+        .count();
+    let response = messages
+        .iter()
+        .find(|message| message["dest"] == "c1")
+        .expect("no reply to the start request");
+    (requests, response["body"].clone())
+}
+
+#[tokio::test(start_paused = true)]
+async fn fetches_a_timestamp_per_call() {
+    let (requests, response) = start_with_timestamps(1, &[5, 8, 13]).await;
+    assert_eq!(3, requests);
+    assert_eq!(json!([5, 8, 13]), response["timestamps"]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn hands_out_prefetched_timestamps_in_order() {
+    // The first batch is answered out of order
+    let (requests, response) = start_with_timestamps(2, &[9, 7, 11, 12]).await;
+    assert_eq!(4, requests);
+    assert_eq!(json!([7, 9, 11]), response["timestamps"]);
+}
+
+#[tokio::test]
+async fn local_timestamps_increase_across_clones() {
+    let tso = TsoClient::local();
+    let clone = tso.clone();
+    let timestamps = [
+        tso.timestamp().await.unwrap(),
+        clone.timestamp().await.unwrap(),
+        tso.timestamp().await.unwrap(),
+    ];
+    assert_eq!([0, 1, 2], timestamps);
+}